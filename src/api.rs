@@ -0,0 +1,324 @@
+use crate::cli::Args;
+use crate::outcome::Report;
+use crate::session::{Recorder, Replay};
+use crate::signing::Signer;
+use crate::tz::{from_club_local, get_nw_date, CLUB_TZ};
+use chrono::{DateTime, Datelike, Utc};
+use eyre::{Error, Result};
+use log::{debug, info, warn};
+use reqwest::redirect::Policy;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BookingsDto {
+    #[serde(rename = "groupActivities")]
+    pub group_activities: Vec<GroupActivity>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupActivity {
+    #[serde(rename = "Id")]
+    pub id: i64,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "ImageUrl")]
+    pub image_url: Option<serde_json::Value>,
+    #[serde(rename = "Description")]
+    pub description: Option<String>,
+    #[serde(rename = "Message")]
+    pub message: Option<String>,
+    #[serde(rename = "Status")]
+    pub status: String,
+    #[serde(rename = "StartTime")]
+    pub start_time: String,
+    #[serde(rename = "EndTime")]
+    pub end_time: String,
+    #[serde(rename = "Location")]
+    pub location: String,
+    #[serde(rename = "Instructor")]
+    pub instructor: String,
+    #[serde(rename = "InstructorId")]
+    pub instructor_id: i64,
+    #[serde(rename = "FreeSlots")]
+    pub free_slots: i64,
+    #[serde(rename = "Dropin")]
+    pub dropin: i64,
+    #[serde(rename = "DropsAmount")]
+    pub drops_amount: i64,
+    #[serde(rename = "BookingId")]
+    pub booking_id: Option<serde_json::Value>,
+    /// Fields without a known meaning, e.g. any capacity the API adds. Kept so
+    /// dumps and recordings show the whole payload and `min_fields` can
+    /// filter on them.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Timeslots of an activity between the club's local dates of `from` and `to`
+pub fn get_bookings_url(
+    user_id: &str,
+    activity_id: &str,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> String {
+    let from = get_nw_date(from);
+    let to = get_nw_date(to);
+    format!("https://api1.nordicwellness.se/GroupActivity/timeslot?clubIds=1&activities={activity_id}&dates={from}%2C{to}&time=&employees=&times=09%3A00-11%3A00%2C17%3A00-22%3A00&datespan=true&userId={user_id}")
+}
+
+/// The span a regular run fetches, a week from now. With
+/// `--publication-weekday` it ends with the publication week that has
+/// started, so the tail of a week that isn't published yet isn't fetched and
+/// mistaken for a week without the class.
+pub fn run_span(args: &Args, now: &DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+    let week = *now + chrono::Duration::weeks(1);
+    let Some(publication_day) = args.publication_weekday else {
+        return (*now, week);
+    };
+    let local = now.with_timezone(&CLUB_TZ).naive_local();
+    let since_publication =
+        (7 + local.weekday().num_days_from_monday() - publication_day.num_days_from_monday()) % 7;
+    let last_published = local + chrono::Duration::days(6 - since_publication as i64);
+    let to = from_club_local(&last_published).with_timezone(&Utc);
+    if get_nw_date(&to) != get_nw_date(&week) {
+        info!(
+            "aligned the span to the week published on {}, fetching until {} instead of {}",
+            publication_day,
+            get_nw_date(&to),
+            get_nw_date(&week)
+        );
+    }
+    (*now, to)
+}
+
+/// Where requests go, the live API or a recorded session
+pub enum Session {
+    Live,
+    Record(Recorder),
+    Replay(Replay),
+}
+
+pub struct Api {
+    client: reqwest::Client,
+    session: Session,
+    fetch_attempts: u32,
+    fetch_retry_delay: std::time::Duration,
+    signer: Signer,
+}
+
+/// Form body of `POST /Booking`. These are the only fields the Nordic Wellness
+/// site is known to send, optional attributes belong here as `Option`s that are
+/// skipped when unset so the API never sees fields it doesn't expect.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct BookingForm<'a> {
+    pub activity_id: u32,
+    pub user_id: u32,
+    pub queue_type: &'a str,
+}
+
+/// A booking the API answered with anything but 200
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: StatusCode,
+    pub text: String,
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "code {}: {}", self.status.as_str(), self.text)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Whether a failed booking may go through when tried again: the request
+/// never reached the API, or the API answered that it was overloaded or rate
+/// limiting. A request that timed out or dropped after it was sent isn't, the
+/// booking may have gone through.
+pub fn is_transient(err: &Error) -> bool {
+    if let Some(err) = err.downcast_ref::<StatusError>() {
+        return err.status.is_server_error() || err.status == StatusCode::TOO_MANY_REQUESTS;
+    }
+    err.downcast_ref::<reqwest::Error>()
+        .is_some_and(|it| it.is_connect())
+}
+
+/// An overloaded API answers with an html error page, often with status 200.
+/// Deserializing it would only give a confusing serde error, so it's turned
+/// into a short error naming the page's title.
+fn html_error(content_type: Option<&str>, text: &str) -> Option<Error> {
+    let is_html =
+        content_type.is_some_and(|it| it.contains("html")) || text.trim_start().starts_with('<');
+    if !is_html {
+        return None;
+    }
+    let title = text
+        .split_once("<title>")
+        .and_then(|(_, rest)| rest.split_once("</title>"))
+        .map_or("no title", |(title, _)| title.trim());
+    Some(Error::msg(format!(
+        "the api returned an html page instead of json ({}), it's most likely overloaded",
+        title
+    )))
+}
+
+/// Requests silently ending up on another host hide a misconfigured base URL or proxy
+fn log_final_url(url: &str, response: &reqwest::Response) {
+    debug!("{} resolved to {}", url, response.url());
+}
+
+impl Api {
+    pub fn from_args(args: &Args) -> Result<Api> {
+        let session = match (&args.record, &args.replay) {
+            (Some(_), Some(_)) => {
+                return Err(Error::msg("--record and --replay can't be combined"))
+            }
+            (Some(dir), None) => Session::Record(Recorder::new(dir)?),
+            (None, Some(dir)) => Session::Replay(Replay::load(dir)?),
+            (None, None) => Session::Live,
+        };
+        let max_redirects = args.max_redirects;
+        let redirect = Policy::custom(move |attempt| {
+            if attempt.previous().len() > max_redirects {
+                return attempt.error(format!("more than {max_redirects} redirects"));
+            }
+            if let Some(from) = attempt.previous().last() {
+                debug!("redirected from {} to {}", from, attempt.url());
+            }
+            attempt.follow()
+        });
+        // The timeout runs until the whole body is read, so a response that
+        // stalls after its headers fails instead of hanging
+        let client = reqwest::Client::builder()
+            .redirect(redirect)
+            .timeout(std::time::Duration::from_secs(args.request_timeout_seconds))
+            .build()?;
+        Ok(Api {
+            client,
+            session,
+            fetch_attempts: args.fetch_retries + 1,
+            fetch_retry_delay: std::time::Duration::from_millis(args.fetch_retry_delay_ms),
+            signer: Signer::load(args.signing.as_deref())?,
+        })
+    }
+
+    pub async fn get_group_activities(&self, url: &str) -> Result<BookingsDto> {
+        let text = self.get_group_activities_text(url).await?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// The unparsed timeslot response, for callers that parse it themselves
+    pub async fn get_group_activities_text(&self, url: &str) -> Result<String> {
+        let text = match &self.session {
+            Session::Replay(replay) => replay.timeslot(url)?,
+            session => {
+                let text = self.fetch(url).await?;
+                if let Session::Record(recorder) = session {
+                    recorder.timeslot(url, &text);
+                }
+                text
+            }
+        };
+        Ok(text)
+    }
+
+    /// Sends the request and reads the body, retrying when either fails
+    async fn fetch(&self, url: &str) -> Result<String> {
+        let mut attempt = 1;
+        loop {
+            let result = async {
+                let request = self.signer.sign(self.client.get(url), "GET", url, "");
+                let response = request.send().await?;
+                log_final_url(url, &response);
+                let content_type = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|it| it.to_str().ok())
+                    .map(String::from);
+                let text = response.text().await?;
+                match html_error(content_type.as_deref(), &text) {
+                    Some(err) => Err(err),
+                    None => Ok(text),
+                }
+            }
+            .await;
+            match result {
+                Ok(text) => return Ok(text),
+                Err(err) if attempt < self.fetch_attempts => {
+                    warn!(
+                        "fetching timeslots failed (attempt {}/{}): {}",
+                        attempt, self.fetch_attempts, err
+                    );
+                    attempt += 1;
+                    tokio::time::sleep(self.fetch_retry_delay).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Books a class, returning the response body of a successful booking.
+    /// A replayed session never sends a booking. A booking is never retried
+    /// here, a body that failed to arrive may still belong to a booking that
+    /// went through, see [`is_transient`] for the ones the retry queue takes.
+    pub async fn book_activity(&self, form: &BookingForm<'_>) -> Result<String> {
+        if let Session::Replay(_) = self.session {
+            return Ok(String::from("replayed session, booking not sent"));
+        }
+        let url = "https://api1.nordicwellness.se/Booking";
+        let body = serde_urlencoded::to_string(form).unwrap();
+
+        let request = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/x-www-form-urlencoded");
+        let response = self
+            .signer
+            .sign(request, "POST", url, &body)
+            .body(body)
+            .send()
+            .await?;
+        log_final_url(url, &response);
+        let status = response.status();
+        let text = response.text().await?;
+        if let Session::Record(recorder) = &self.session {
+            recorder.booking(&serde_json::to_value(form)?, status.as_u16(), &text);
+        }
+        if status != StatusCode::OK {
+            return Err(StatusError { status, text }.into());
+        }
+        Ok(text)
+    }
+
+    /// How far the API's clock is ahead of the local one, from the `Date`
+    /// header of a request to it. The header only has whole seconds, so the
+    /// skew is at best accurate to half a second. None when replaying or
+    /// when the API sends no date.
+    pub async fn clock_skew(&self) -> Result<Option<chrono::Duration>> {
+        if let Session::Replay(_) = self.session {
+            return Ok(None);
+        }
+        let sent = Utc::now();
+        let url = "https://api1.nordicwellness.se/";
+        let response = self
+            .signer
+            .sign(self.client.head(url), "HEAD", url, "")
+            .send()
+            .await?;
+        let received = Utc::now();
+        let date = match response.headers().get(reqwest::header::DATE) {
+            Some(date) => DateTime::parse_from_rfc2822(date.to_str()?)?,
+            None => return Ok(None),
+        };
+        let local = sent + (received - sent) / 2;
+        Ok(Some(date.with_timezone(&Utc) - local))
+    }
+
+    pub fn record_decision(&self, report: &Report) {
+        if let Session::Record(recorder) = &self.session {
+            recorder.decision(report);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Keeps one user's dead credentials from failing the rest of a config. A
+//! booking answered with one of `--auth-failure-codes` blocks the user for
+//! the life of the process: their remaining activities are reported as
+//! blocked without sending anything, while other users carry on.
+use crate::api::StatusError;
+use crate::cli::Args;
+use crate::BookableActivity;
+use eyre::Error;
+use log::error;
+use std::sync::Mutex;
+
+static BLOCKED: Mutex<Vec<u32>> = Mutex::new(vec![]);
+
+/// Why the activity's user is blocked, if they are
+pub fn blocked(activity: &BookableActivity) -> Option<String> {
+    BLOCKED
+        .lock()
+        .unwrap()
+        .contains(&activity.user_id)
+        .then(|| reason(activity))
+}
+
+/// Blocks the activity's user when the booking failed for lack of auth,
+/// returning why
+pub fn block_on(args: &Args, activity: &BookableActivity, err: &Error) -> Option<String> {
+    let status = err.downcast_ref::<StatusError>()?.status.as_u16();
+    if !args.auth_failure_codes.contains(&status) {
+        return None;
+    }
+    let mut blocked = BLOCKED.lock().unwrap();
+    if !blocked.contains(&activity.user_id) {
+        blocked.push(activity.user_id);
+        error!(
+            "user {} auth failed with code {}, not booking anything else for them: {}",
+            activity.user_name, status, err
+        );
+    }
+    Some(reason(activity))
+}
+
+fn reason(activity: &BookableActivity) -> String {
+    format!("auth failed for user {}", activity.user_name)
+}
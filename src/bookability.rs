@@ -0,0 +1,113 @@
+//! Whether a class can be booked for an activity. Booking, dry runs and watch
+//! mode all decide it here so they never disagree about a class.
+use crate::api::GroupActivity;
+use crate::cli::{Args, ZeroFreeSlots};
+use crate::tz::from_club_local;
+use crate::BookableActivity;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Availability {
+    Bookable,
+    /// Not taking bookings, because it hasn't opened yet, has closed or is a
+    /// drop-in class with `--skip-dropin`
+    Closed,
+    /// No spot left, going by the free slots or a full phrase in the message
+    Full,
+    /// Has free slots but fewer than the activity's `min_free_slots`
+    TooCrowded,
+}
+
+/// Whether an activity takes an ordinary spot, joins the waitlist or both
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BookingPreference {
+    #[default]
+    BookOnly,
+    /// An ordinary spot when there is one, the waitlist of a full class otherwise
+    BookOrQueue,
+    /// Always the waitlist, even when a spot is free
+    QueueOnly,
+}
+
+/// A class the activity would book or queue for, see [`queues_for`]
+pub fn is_candidate(args: &Args, activity: &BookableActivity, class: &GroupActivity) -> bool {
+    match availability(args, activity, class) {
+        Availability::Bookable => true,
+        Availability::Full => {
+            activity.booking_preference.unwrap_or_default() != BookingPreference::BookOnly
+        }
+        _ => false,
+    }
+}
+
+/// Whether booking the class joins its waitlist rather than taking a spot
+pub fn queues_for(args: &Args, activity: &BookableActivity, class: &GroupActivity) -> bool {
+    match activity.booking_preference.unwrap_or_default() {
+        BookingPreference::BookOnly => false,
+        BookingPreference::BookOrQueue => {
+            !has_free_spot(args, class) || availability(args, activity, class) == Availability::Full
+        }
+        BookingPreference::QueueOnly => true,
+    }
+}
+
+/// Whether the free slots leave an ordinary spot to take. `FreeSlots` has been
+/// seen below 0, which is taken to mean the class is overbooked and its
+/// waitlist has people on it, so that is never a spot. What 0 means is less
+/// clear, `--zero-free-slots dropin` counts the class's `Dropin` spots then.
+pub fn has_free_spot(args: &Args, class: &GroupActivity) -> bool {
+    match class.free_slots {
+        slots if slots > 0 => true,
+        0 => args.zero_free_slots == ZeroFreeSlots::Dropin && class.dropin > 0,
+        _ => false,
+    }
+}
+
+/// `Status` decides first since it's the API's own verdict. A `Message`
+/// containing one of `--full-phrases` overrides it, as do negative free slots.
+/// With 0 free slots a class is only booked when the status is trusted, which
+/// lets waitlisted classes through, unless `--require-free-slots` or
+/// `--zero-free-slots` say otherwise.
+pub fn availability(
+    args: &Args,
+    activity: &BookableActivity,
+    class: &GroupActivity,
+) -> Availability {
+    if class.status != "Bookable" || (args.skip_dropin && class.dropin > 0) {
+        return Availability::Closed;
+    }
+    let message = class.message.as_deref().unwrap_or_default().to_lowercase();
+    let says_full = args
+        .full_phrases
+        .iter()
+        .any(|phrase| !phrase.is_empty() && message.contains(&phrase.to_lowercase()));
+    let trusts_status = args.zero_free_slots == ZeroFreeSlots::Status && !args.require_free_slots;
+    if says_full || class.free_slots < 0 || (!trusts_status && !has_free_spot(args, class)) {
+        return Availability::Full;
+    }
+    match activity.min_free_slots {
+        Some(min) if class.free_slots < min => Availability::TooCrowded,
+        _ => Availability::Bookable,
+    }
+}
+
+/// When the club says the class opens for booking. No dedicated field for it
+/// has been seen in the timeslot response, so this reads a `Message` like
+/// `Bokningsbar från 2024-03-05 07:00` with the time in club local time.
+pub fn bookable_from(class: &GroupActivity) -> Option<DateTime<Utc>> {
+    let message = class.message.as_deref()?.to_lowercase();
+    let keyword = ["bokningsbar", "bookable"]
+        .iter()
+        .filter_map(|it| message.find(it))
+        .min()?;
+    message
+        .char_indices()
+        .filter(|(index, _)| *index > keyword)
+        .find_map(|(index, _)| {
+            let candidate = message.get(index..index + "2024-03-05 07:00".len())?;
+            NaiveDateTime::parse_from_str(candidate, "%Y-%m-%d %H:%M").ok()
+        })
+        .map(|it| from_club_local(&it).with_timezone(&Utc))
+}
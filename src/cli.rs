@@ -0,0 +1,421 @@
+use crate::outcome::BookingOutcome;
+use chrono::{DateTime, NaiveDate, Utc, Weekday};
+use clap::{ArgAction, Parser, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(about = "Books gym classes automatically using the Nordic Wellness API")]
+pub struct Args {
+    /// Print the class every activity would book each day of the coming week and exit, nothing is booked
+    #[arg(long, env = "PREVIEW")]
+    pub preview: bool,
+
+    /// Write the class every enabled activity would book this week to a sorted file to diff across runs, then exit
+    #[arg(long, env = "NW_PLAN", conflicts_with = "preview")]
+    pub plan: Option<PathBuf>,
+
+    /// Format of the log lines, json adds one booking_decision object per activity
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Print a single summary line of the run to stdout, the logs stay on stderr
+    #[arg(long, env = "ONELINE")]
+    pub oneline: bool,
+
+    /// Outcomes that make the run exit with code 1
+    #[arg(
+        long,
+        env = "EXIT_ON",
+        value_delimiter = ',',
+        default_value = "failed,blocked",
+        value_parser = clap::builder::PossibleValuesParser::new(BookingOutcome::KINDS)
+    )]
+    pub exit_on: Vec<String>,
+
+    /// Write the booked classes to an iCalendar file, tentative in a dry run or for a waitlist
+    #[arg(long, env = "ICS")]
+    pub ics: Option<PathBuf>,
+
+    /// Find the classes that would be booked without booking them
+    #[arg(long, env = "DRY_RUN")]
+    pub dry_run: bool,
+
+    /// How activities are run. unbounded runs them all at once, global at most
+    /// --max-concurrency at a time and per-user one at a time per user with users in parallel
+    #[arg(long, env = "CONCURRENCY_MODE", value_enum, default_value_t = ConcurrencyMode::Unbounded)]
+    pub concurrency_mode: ConcurrencyMode,
+
+    /// Activities run at once with --concurrency-mode global
+    #[arg(long, env = "MAX_CONCURRENCY", default_value_t = 4)]
+    pub max_concurrency: usize,
+
+    /// Json file mapping class names to their names in the other language, e.g. {"Cycling": ["Cykel"]}
+    #[arg(long, env = "TRANSLATIONS")]
+    pub translations: Option<PathBuf>,
+
+    /// How to pick between classes that score the same, first keeps the order of the API response
+    #[arg(long, env = "TIE_BREAK", value_enum, default_value_t = TieBreak::First)]
+    pub tie_break: TieBreak,
+
+    /// With --tie-break random, classes scoring at most this much below the best count as tied
+    #[arg(long, env = "TIE_TOLERANCE", default_value_t = 0.0)]
+    pub tie_tolerance: f64,
+
+    /// Seed of --tie-break random, for reproducible picks
+    #[arg(long, env = "SEED")]
+    pub seed: Option<u64>,
+
+    /// Phrases in a class message that mean it's full whatever its status says
+    #[arg(
+        long,
+        env = "FULL_PHRASES",
+        value_delimiter = ',',
+        default_value = "fullbokad"
+    )]
+    pub full_phrases: Vec<String>,
+
+    /// Never book a class without free slots, even when its status says it's bookable
+    #[arg(long, env = "REQUIRE_FREE_SLOTS")]
+    pub require_free_slots: bool,
+
+    /// What a class with 0 free slots and a bookable status is: bookable going by the status, full, or bookable only when it has drop-in spots
+    #[arg(long, env = "ZERO_FREE_SLOTS", value_enum, default_value_t = ZeroFreeSlots::Status)]
+    pub zero_free_slots: ZeroFreeSlots,
+
+    /// QueueType sent to join a waitlist for activities with a booking_preference that queues, no value is confirmed yet
+    #[arg(long, env = "WAITLIST_QUEUE_TYPE")]
+    pub waitlist_queue_type: Option<String>,
+
+    /// Never book drop-in classes
+    #[arg(long, env = "SKIP_DROPIN")]
+    pub skip_dropin: bool,
+
+    /// Keep polling an activity until a class is booked or --poll-timeout-minutes passes
+    #[arg(long, env = "RETRY_UNTIL_BOOKED")]
+    pub retry_until_booked: bool,
+
+    /// Seconds between polls with --retry-until-booked
+    #[arg(long, env = "POLL_INTERVAL_SECONDS", default_value_t = 30)]
+    pub poll_interval_seconds: u64,
+
+    /// Minutes after which --retry-until-booked gives up
+    #[arg(long, env = "POLL_TIMEOUT_MINUTES", default_value_t = 10)]
+    pub poll_timeout_minutes: u64,
+
+    /// Keep running and book every matching class within the horizon the moment its booking opens
+    #[arg(long, env = "WATCH")]
+    pub watch: bool,
+
+    /// Watch mode that remembers booked occurrences in --state, keeping every activity booked ahead across restarts
+    #[arg(long, env = "ROLLING", requires = "state")]
+    pub rolling: bool,
+
+    /// Most bookings queued in --state for a retry after failing transiently in watch mode
+    #[arg(long, env = "RETRY_QUEUE_SIZE", default_value_t = 20)]
+    pub retry_queue_size: usize,
+
+    /// Seconds between retries of a queued booking
+    #[arg(long, env = "RETRY_INTERVAL_SECONDS", default_value_t = 30)]
+    pub retry_interval_seconds: i64,
+
+    /// Minutes after its first failure a queued booking is given up on
+    #[arg(long, env = "RETRY_DEADLINE_MINUTES", default_value_t = 30)]
+    pub retry_deadline_minutes: i64,
+
+    /// How many weeks ahead watch mode schedules bookings
+    #[arg(long, env = "HORIZON_WEEKS", default_value_t = 2)]
+    pub horizon_weeks: i64,
+
+    /// How many days before a class starts its booking opens
+    #[arg(long, env = "BOOKING_OPEN_DAYS_BEFORE", default_value_t = 7)]
+    pub booking_open_days_before: i64,
+
+    /// Minutes between watch mode refreshes of the schedule, picking up newly listed classes
+    #[arg(long, env = "WATCH_REFRESH_MINUTES", default_value_t = 60)]
+    pub watch_refresh_minutes: i64,
+
+    /// Keep polling the full classes of activities with monitor_waitlist and book one once a spot frees up
+    #[arg(long, env = "MONITOR_WAITLIST", conflicts_with_all = ["watch", "rolling"])]
+    pub monitor_waitlist: bool,
+
+    /// Seconds between polls with --monitor-waitlist
+    #[arg(long, env = "WAITLIST_POLL_SECONDS", default_value_t = 60)]
+    pub waitlist_poll_seconds: u64,
+
+    /// Warn when watch mode finds the local clock off from the api's by more than this
+    #[arg(long, env = "MAX_CLOCK_SKEW_MS", default_value_t = 1000)]
+    pub max_clock_skew_ms: i64,
+
+    /// Fire watch mode bookings by the api's clock rather than the local one
+    #[arg(long, env = "COMPENSATE_CLOCK_SKEW")]
+    pub compensate_clock_skew: bool,
+
+    /// Free slots a class needs on the day it starts to be reported as likely
+    #[arg(long, env = "LIKELY_FREE_SLOTS", default_value_t = 3)]
+    pub likely_free_slots: i64,
+
+    /// Extra free slots needed to be reported as likely per day until the class starts
+    #[arg(long, env = "LIKELY_FREE_SLOTS_PER_DAY", default_value_t = 2)]
+    pub likely_free_slots_per_day: i64,
+
+    /// File to remember outcomes between runs in, nothing is remembered when unset
+    #[arg(long, env = "STATE_FILE")]
+    pub state: Option<PathBuf>,
+
+    /// Send a test message with every configured notifier, report which ones work and exit
+    #[arg(long, env = "NW_CHECK_NOTIFIERS")]
+    pub check_notifiers: bool,
+
+    /// Check the config, the api and the notifiers without booking anything and exit
+    #[arg(long, env = "NW_SELFTEST")]
+    pub selftest: bool,
+
+    /// Print the activities as read from the config, with their days expanded, and exit
+    #[arg(long, env = "NW_DUMP_CONFIG")]
+    pub dump_config: bool,
+
+    /// Print the stats of every activity recorded in --state and exit
+    #[arg(long, env = "NW_STATS", requires = "state")]
+    pub stats: bool,
+
+    /// Print the classes booked in the week, e.g. 2024-W10 or any date in it, from the history in --state and exit
+    #[arg(long, env = "NW_WEEKLY_REPORT", requires = "state", value_parser = crate::weekly::parse_week)]
+    pub weekly_report: Option<NaiveDate>,
+
+    /// How --weekly-report is printed
+    #[arg(long, env = "NW_REPORT_FORMAT", value_enum, default_value_t = ReportFormat::Table)]
+    pub report_format: ReportFormat,
+
+    /// Week day the club publishes a new week of classes on, a regular run then doesn't fetch past the published week
+    #[arg(long, env = "PUBLICATION_WEEKDAY", value_parser = parse_weekday)]
+    pub publication_weekday: Option<Weekday>,
+
+    /// Warn at startup when the tz database built into the binary is more than this many years old
+    #[arg(long, env = "MAX_TZ_AGE_YEARS", default_value_t = 2)]
+    pub max_tz_age_years: i32,
+
+    /// Warn when the activities file is older than this many days and some activity keeps finding no class
+    #[arg(long, env = "STALE_CONFIG_DAYS")]
+    pub stale_config_days: Option<i64>,
+
+    /// Exit right away when the last run recorded in --state was less than this many minutes ago
+    #[arg(long, env = "MIN_RUN_INTERVAL_MINUTES", requires = "state")]
+    pub min_run_interval_minutes: Option<i64>,
+
+    /// Run even when --min-run-interval-minutes hasn't passed since the last run
+    #[arg(long, env = "FORCE")]
+    pub force: bool,
+
+    /// Days back no-shows in --state count towards an activity's max_no_shows
+    #[arg(long, env = "NO_SHOW_WINDOW_DAYS", default_value_t = 60)]
+    pub no_show_window_days: i64,
+
+    /// Write the requests, responses and decisions of this run to the directory, with user ids redacted
+    #[arg(long, env = "RECORD")]
+    pub record: Option<PathBuf>,
+
+    /// Run as if it were this time, e.g. 2024-03-04T06:59:00Z. Only with --dry-run, --replay, --preview or --plan
+    #[arg(
+        long,
+        env = "NW_FAKE_NOW",
+        value_parser = parse_rfc3339,
+        conflicts_with_all = ["watch", "rolling", "monitor_waitlist"]
+    )]
+    pub now: Option<DateTime<Utc>>,
+
+    /// Run against the responses recorded in the directory instead of the API, nothing is booked
+    #[arg(long, env = "REPLAY")]
+    pub replay: Option<PathBuf>,
+
+    /// Json file configuring how requests to the api are signed, nothing is signed when unset
+    #[arg(long, env = "SIGNING_CONFIG")]
+    pub signing: Option<PathBuf>,
+
+    /// Mask user names, user ids and notifier tokens in every log line with a short hash of the value
+    #[arg(long, env = "REDACT_PII")]
+    pub redact: bool,
+
+    /// Seconds a request to the api may take, including reading the whole response
+    #[arg(long, env = "REQUEST_TIMEOUT_SECONDS", default_value_t = 30)]
+    pub request_timeout_seconds: u64,
+
+    /// Http codes of a booking response meaning the user's auth failed, their remaining activities are blocked instead of attempted
+    #[arg(
+        long,
+        env = "AUTH_FAILURE_CODES",
+        value_delimiter = ',',
+        default_value = "401,403"
+    )]
+    pub auth_failure_codes: Vec<u16>,
+
+    /// Fetch the class right before booking it and skip it when the user already has it booked, costs one request per booking
+    #[arg(long, env = "CHECK_BEFORE_BOOK", default_value_t = true, action = ArgAction::Set)]
+    pub check_before_book: bool,
+
+    /// Times the class is fetched after booking it to confirm the booking shows up, 0 doesn't confirm
+    #[arg(long, env = "CONFIRM_POLLS", default_value_t = 0)]
+    pub confirm_polls: u32,
+
+    /// Milliseconds before the first confirmation poll, doubling for every poll after it
+    #[arg(long, env = "CONFIRM_INTERVAL_MS", default_value_t = 500)]
+    pub confirm_interval_ms: u64,
+
+    /// Seconds confirming a booking may take in total
+    #[arg(long, env = "CONFIRM_TIMEOUT_SECONDS", default_value_t = 10)]
+    pub confirm_timeout_seconds: u64,
+
+    /// When the run summary is sent: always, when an outcome changed since the last run or anything failed, or only when something failed
+    #[arg(long, env = "NOTIFY_POLICY", value_enum, default_value_t = NotifyPolicy::Always)]
+    pub notify_policy: NotifyPolicy,
+
+    /// Don't send a run summary identical to one sent within this many minutes, needs --state, 0 sends every one
+    #[arg(long, env = "NOTIFY_DEDUP_MINUTES", default_value_t = 0)]
+    pub notify_dedup_minutes: i64,
+
+    /// What to do with a class that overlaps another class booked for the same user
+    #[arg(long, env = "OVERLAP_POLICY", value_enum, default_value_t = OverlapPolicy::Warn)]
+    pub overlap_policy: OverlapPolicy,
+
+    /// Order the activities are booked in, as configured or the soonest starting class first
+    #[arg(long, env = "ORDER_BY", value_enum, default_value_t = OrderBy::Config)]
+    pub order_by: OrderBy,
+
+    /// Activities of a group booked per user and day before the rest of the group is skipped
+    #[arg(long, env = "GROUP_DAILY_CAP", default_value_t = 1)]
+    pub group_daily_cap: usize,
+
+    /// Times a timeslot response without any classes is fetched again before the activity counts as not found
+    #[arg(long, env = "EMPTY_RETRIES", default_value_t = 2)]
+    pub empty_retries: u32,
+
+    /// Milliseconds between fetching an empty timeslot response again
+    #[arg(long, env = "EMPTY_RETRY_DELAY_MS", default_value_t = 300)]
+    pub empty_retry_delay_ms: u64,
+
+    /// Most classes of one timeslot response an activity is matched against, past it the matching and the soonest are kept
+    #[arg(long, env = "MAX_CANDIDATES", default_value_t = 2000)]
+    pub max_candidates: usize,
+
+    /// Milliseconds parsing and matching the timeslots of an activity may take before it fails instead of booking
+    #[arg(long, env = "PROCESSING_BUDGET_MS", default_value_t = 5000)]
+    pub processing_budget_ms: u64,
+
+    /// Times a failed or timed out timeslot fetch is retried right away, bookings are never retried
+    #[arg(long, env = "FETCH_RETRIES", default_value_t = 2)]
+    pub fetch_retries: u32,
+
+    /// Milliseconds to wait before retrying a timeslot fetch
+    #[arg(long, env = "FETCH_RETRY_DELAY_MS", default_value_t = 500)]
+    pub fetch_retry_delay_ms: u64,
+
+    /// Redirects followed by requests to the API before giving up, every redirect is logged at debug
+    #[arg(long, env = "MAX_REDIRECTS", default_value_t = 10)]
+    pub max_redirects: usize,
+
+    /// Save the raw response of every successful booking to the directory, unredacted
+    #[arg(long, env = "SAVE_RESPONSES")]
+    pub save_responses: Option<PathBuf>,
+
+    /// Telegram bot token used to send the run summary
+    #[arg(long, env = "TELEGRAM_BOT_TOKEN", hide_env_values = true)]
+    pub telegram_bot_token: Option<String>,
+
+    /// Telegram chat the run summary is sent to
+    #[arg(long, env = "TELEGRAM_CHAT_ID")]
+    pub telegram_chat_id: Option<String>,
+
+    /// URL the run summary is POSTed to as json
+    #[arg(long, env = "NOTIFY_WEBHOOK_URL", hide_env_values = true)]
+    pub notify_webhook_url: Option<String>,
+
+    /// Json file listing more notifiers, each with an optional on filter of outcomes it's sent
+    #[arg(long, env = "NOTIFIERS")]
+    pub notifiers: Option<PathBuf>,
+
+    /// Shell command run for every booked activity, the details are in NW_* environment variables
+    #[arg(long, env = "ON_SUCCESS_COMMAND")]
+    pub on_success_command: Option<String>,
+
+    /// Shell command run for every activity that failed, the details are in NW_* environment variables
+    #[arg(long, env = "ON_FAILURE_COMMAND")]
+    pub on_failure_command: Option<String>,
+
+    /// Show the run summary as a desktop notification, needs a build with the desktop-notify feature
+    #[arg(long, env = "DESKTOP_NOTIFY")]
+    pub desktop_notify: bool,
+
+    /// Seconds a notifier may take to deliver the run summary
+    #[arg(long, env = "NOTIFY_TIMEOUT_SECONDS", default_value_t = 10)]
+    pub notify_timeout_seconds: u64,
+
+    /// How many times each notifier tries to deliver the run summary
+    #[arg(long, env = "NOTIFY_RETRY_ATTEMPTS", default_value_t = 3)]
+    pub notify_retry_attempts: u32,
+
+    /// Milliseconds to wait between notification attempts
+    #[arg(long, env = "NOTIFY_RETRY_DELAY_MS", default_value_t = 2000)]
+    pub notify_retry_delay_ms: u64,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrencyMode {
+    Unbounded,
+    Global,
+    PerUser,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    First,
+    Random,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderBy {
+    Config,
+    StartTime,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyPolicy {
+    Always,
+    OnChange,
+    OnFailure,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    Warn,
+    /// Don't book the class that was claimed second
+    SkipSecond,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroFreeSlots {
+    Status,
+    Full,
+    /// Bookable when `Dropin` is above 0, i.e. the spots left are drop-in ones
+    Dropin,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Table,
+    Csv,
+}
+
+fn parse_rfc3339(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|it| it.with_timezone(&Utc))
+        .map_err(|err| err.to_string())
+}
+
+fn parse_weekday(value: &str) -> Result<Weekday, String> {
+    crate::parse_weekday(value).ok_or_else(|| format!("invalid week day {}", value))
+}
@@ -0,0 +1,29 @@
+//! The time the date logic runs at. `--now` fixes it so a past run's decisions
+//! can be reproduced, or time dependent behaviour tried without waiting for
+//! it. A fixed time never advances, so it's only allowed in runs that can't
+//! book and don't keep running.
+use chrono::{DateTime, Utc};
+use eyre::{bail, Result};
+use log::warn;
+use std::sync::OnceLock;
+
+use crate::cli::Args;
+
+static FIXED: OnceLock<DateTime<Utc>> = OnceLock::new();
+
+/// Fixes the clock to `--now` when it's set and the run can't book
+pub fn init(args: &Args) -> Result<()> {
+    let Some(now) = args.now else {
+        return Ok(());
+    };
+    if !args.dry_run && args.replay.is_none() && !args.preview && args.plan.is_none() {
+        bail!("--now only works with --dry-run, --replay, --preview or --plan");
+    }
+    warn!("running as if it were {}", now.to_rfc3339());
+    FIXED.get_or_init(|| now);
+    Ok(())
+}
+
+pub fn now() -> DateTime<Utc> {
+    FIXED.get().copied().unwrap_or_else(Utc::now)
+}
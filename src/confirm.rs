@@ -0,0 +1,138 @@
+//! `--confirm-polls` checks that a booking went through by fetching the class
+//! again until it carries the user's `BookingId`. The API can take a moment
+//! to reflect a booking, so the fetches back off, doubling
+//! `--confirm-interval-ms` each time, for at most `--confirm-timeout-seconds`.
+//! `--check-before-book` looks for the same id once before booking, so a
+//! rerun doesn't send a booking the API would turn down as a duplicate.
+use crate::api::{get_bookings_url, Api, GroupActivity};
+use crate::cli::Args;
+use crate::tz::parse_date;
+use crate::BookableActivity;
+use chrono::Utc;
+use eyre::Result;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Confirmation {
+    /// The class showed up with a booking id
+    Confirmed,
+    /// The class was fetched, but the booking wasn't in it yet by the last poll
+    NotVisible,
+    /// No poll got an answer before the timeout, so nothing is known
+    Unconfirmed,
+}
+
+impl fmt::Display for Confirmation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Confirmation::Confirmed => write!(f, "confirmed"),
+            Confirmation::NotVisible => write!(f, "accepted but not yet visible"),
+            Confirmation::Unconfirmed => write!(f, "unconfirmed, timed out"),
+        }
+    }
+}
+
+/// Whether the user already has the class booked, going by the class as it
+/// was fetched and then by fetching its day again. A failed fetch counts as
+/// not booked, the booking itself then finds out.
+pub async fn already_booked(
+    args: &Args,
+    api: &Api,
+    activity: &BookableActivity,
+    class: &GroupActivity,
+) -> bool {
+    if !args.check_before_book {
+        return false;
+    }
+    if class.booking_id.is_some() {
+        return true;
+    }
+    match is_booked(api, activity, class).await {
+        Ok(booked) => booked,
+        Err(err) => {
+            warn!(
+                "unable to check whether {} is already booked, booking it: {}",
+                class.name, err
+            );
+            false
+        }
+    }
+}
+
+/// Fetches the day of the class to see whether it carries the user's booking
+pub async fn is_booked(
+    api: &Api,
+    activity: &BookableActivity,
+    class: &GroupActivity,
+) -> Result<bool> {
+    let start = parse_date(&class.start_time).with_timezone(&Utc);
+    let url = get_bookings_url(&activity.user_id.to_string(), &activity.id, &start, &start);
+    let dto = api.get_group_activities(&url).await?;
+    Ok(dto
+        .group_activities
+        .iter()
+        .any(|it| it.id == class.id && it.booking_id.is_some()))
+}
+
+/// Polls for the booking of the class, `None` when confirmation is off
+pub async fn confirm(
+    args: &Args,
+    api: &Api,
+    activity: &BookableActivity,
+    class: &GroupActivity,
+) -> Option<Confirmation> {
+    if args.confirm_polls == 0 {
+        return None;
+    }
+    let start = parse_date(&class.start_time).with_timezone(&Utc);
+    let url = get_bookings_url(&activity.user_id.to_string(), &activity.id, &start, &start);
+    let deadline = Instant::now() + Duration::from_secs(args.confirm_timeout_seconds);
+    let mut delay = Duration::from_millis(args.confirm_interval_ms);
+    let mut fetched = false;
+    for poll in 1..=args.confirm_polls {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining < delay {
+            break;
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let dto = match tokio::time::timeout(remaining, api.get_group_activities(&url)).await {
+            Ok(Ok(dto)) => dto,
+            Ok(Err(err)) => {
+                warn!("unable to confirm the booking of {}: {}", class.name, err);
+                continue;
+            }
+            Err(_) => break,
+        };
+        fetched = true;
+        let booked = dto
+            .group_activities
+            .iter()
+            .any(|it| it.id == class.id && it.booking_id.is_some());
+        if booked {
+            info!(
+                "confirmed the booking of {} at {} after {} polls",
+                class.name, class.start_time, poll
+            );
+            return Some(Confirmation::Confirmed);
+        }
+        debug!(
+            "the booking of {} isn't visible after {} polls",
+            class.name, poll
+        );
+    }
+    let confirmation = match fetched {
+        true => Confirmation::NotVisible,
+        false => Confirmation::Unconfirmed,
+    };
+    warn!(
+        "the booking of {} at {} is {}",
+        class.name, class.start_time, confirmation
+    );
+    Some(confirmation)
+}
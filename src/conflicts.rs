@@ -0,0 +1,102 @@
+//! Catches configs that would book a user into two classes at once. Every
+//! class that is booked, or that the timeslots show the user has booked
+//! already, is claimed for its time range, and a class overlapping one the
+//! user already has is handled by `--overlap-policy`. Claims are kept for the
+//! life of the process, so watch mode checks across days of firing too.
+use crate::api::GroupActivity;
+use crate::cli::{Args, OverlapPolicy};
+use crate::tz::parse_date;
+use crate::{clock, BookableActivity};
+use chrono::{DateTime, FixedOffset};
+use log::warn;
+use std::sync::Mutex;
+
+struct Claim {
+    user_id: u32,
+    class_id: i64,
+    name: String,
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+}
+
+impl Claim {
+    fn new(activity: &BookableActivity, class: &GroupActivity) -> Claim {
+        Claim {
+            user_id: activity.user_id,
+            class_id: class.id,
+            name: class.name.clone(),
+            start: parse_date(&class.start_time),
+            end: parse_date(&class.end_time),
+        }
+    }
+
+    fn overlaps(&self, other: &Claim) -> bool {
+        self.user_id == other.user_id
+            && self.class_id != other.class_id
+            && self.start < other.end
+            && other.start < self.end
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} {} to {}",
+            self.name,
+            self.start.format("%Y-%m-%d %H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+static CLAIMS: Mutex<Vec<Claim>> = Mutex::new(vec![]);
+
+/// Claims the classes the user has already booked, as far as the timeslots show
+pub fn note_booked(activity: &BookableActivity, classes: &[GroupActivity]) {
+    let mut claims = CLAIMS.lock().unwrap();
+    for class in classes.iter().filter(|it| it.booking_id.is_some()) {
+        if !claims
+            .iter()
+            .any(|it| it.user_id == activity.user_id && it.class_id == class.id)
+        {
+            claims.push(Claim::new(activity, class));
+        }
+    }
+}
+
+/// Claims the class before it's booked. Returns why it's skipped when it
+/// overlaps another class of the user and the policy is `skip-second`.
+pub fn claim(args: &Args, activity: &BookableActivity, class: &GroupActivity) -> Option<String> {
+    let now = clock::now();
+    let claim = Claim::new(activity, class);
+    let mut claims = CLAIMS.lock().unwrap();
+    claims.retain(|it| it.end > now);
+    if let Some(other) = claims.iter().find(|it| it.overlaps(&claim)) {
+        let conflict = format!(
+            "{} overlaps {} for user {}",
+            claim.describe(),
+            other.describe(),
+            activity.user_name
+        );
+        match args.overlap_policy {
+            OverlapPolicy::Warn => warn!("{}, booking it anyway", conflict),
+            OverlapPolicy::SkipSecond => {
+                warn!("{}, not booking it", conflict);
+                return Some(conflict);
+            }
+        }
+    }
+    if !claims
+        .iter()
+        .any(|it| it.user_id == claim.user_id && it.class_id == claim.class_id)
+    {
+        claims.push(claim);
+    }
+    None
+}
+
+/// Releases the claim of a class whose booking failed
+pub fn release(activity: &BookableActivity, class: &GroupActivity) {
+    CLAIMS
+        .lock()
+        .unwrap()
+        .retain(|it| !(it.user_id == activity.user_id && it.class_id == class.id));
+}
@@ -0,0 +1,50 @@
+//! `--on-success-command` and `--on-failure-command` run a shell command for
+//! every booked or failed activity, with the details in `NW_*` environment
+//! variables. A command that fails is logged and never fails the run.
+use crate::cli::Args;
+use crate::outcome::{BookingOutcome, Report};
+use log::{info, warn};
+use tokio::process::Command;
+
+pub async fn run(args: &Args, reports: &[Report]) {
+    for report in reports {
+        let command = match report.outcome {
+            BookingOutcome::Booked { .. } => &args.on_success_command,
+            BookingOutcome::Failed { .. } | BookingOutcome::Blocked { .. } => {
+                &args.on_failure_command
+            }
+            _ => &None,
+        };
+        if let Some(command) = command {
+            run_command(command, report).await;
+        }
+    }
+}
+
+async fn run_command(command: &str, report: &Report) {
+    let (class, start_time) = match &report.outcome {
+        BookingOutcome::Booked {
+            class, start_time, ..
+        } => (class.as_str(), start_time.as_str()),
+        _ => ("", ""),
+    };
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("NW_ACTIVITY", &report.activity)
+        .env("NW_ACTIVITY_KEY", &report.key)
+        .env("NW_USER", &report.user_name)
+        .env("NW_CLASS", class)
+        .env("NW_START_TIME", start_time)
+        .env("NW_RESULT", report.outcome.kind())
+        .env("NW_MESSAGE", report.outcome.to_string())
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => {
+            info!("{} for {} exited with {}", command, report.key, status)
+        }
+        Ok(status) => warn!("{} for {} exited with {}", command, report.key, status),
+        Err(err) => warn!("unable to run {} for {}: {}", command, report.key, err),
+    }
+}
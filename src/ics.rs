@@ -0,0 +1,76 @@
+//! `--ics` writes the classes of a run as iCalendar events. Booked classes are
+//! confirmed, classes a dry run would book and waitlists are tentative. The
+//! uid only depends on the activity and class, so importing the events of a
+//! real run after a dry run turns the tentative events into confirmed ones.
+use crate::outcome::{BookingOutcome, Report};
+use crate::tz::parse_date;
+use chrono::{DateTime, Utc};
+use eyre::Result;
+use std::fs;
+use std::path::Path;
+
+pub fn write(path: &Path, reports: &[Report]) -> Result<()> {
+    let now = Utc::now();
+    let mut lines = vec![
+        String::from("BEGIN:VCALENDAR"),
+        String::from("VERSION:2.0"),
+        String::from("PRODID:-//nordic_wellness_booker//EN"),
+    ];
+    for report in reports {
+        let (class, start_time, end_time, status) = match &report.outcome {
+            BookingOutcome::Booked {
+                class,
+                start_time,
+                end_time,
+                ..
+            } => (class, start_time, end_time, "CONFIRMED"),
+            BookingOutcome::Queued {
+                class,
+                start_time,
+                end_time,
+            }
+            | BookingOutcome::WouldBook {
+                class,
+                start_time,
+                end_time,
+                ..
+            } => (class, start_time, end_time, "TENTATIVE"),
+            _ => continue,
+        };
+        let start = parse_date(start_time).with_timezone(&Utc);
+        let end = parse_date(end_time).with_timezone(&Utc);
+        lines.extend([
+            String::from("BEGIN:VEVENT"),
+            format!("UID:{}", uid(&report.key, start_time)),
+            format!("DTSTAMP:{}", format_time(&now)),
+            format!("DTSTART:{}", format_time(&start)),
+            format!("DTEND:{}", format_time(&end)),
+            format!("SUMMARY:{}", escape(class)),
+            format!("DESCRIPTION:{}", escape(&report.outcome.to_string())),
+            format!("STATUS:{}", status),
+            String::from("END:VEVENT"),
+        ]);
+    }
+    lines.push(String::from("END:VCALENDAR"));
+    fs::write(path, lines.join("\r\n") + "\r\n")?;
+    Ok(())
+}
+
+fn uid(key: &str, start_time: &str) -> String {
+    let id: String = format!("{}-{}", key, start_time)
+        .chars()
+        .map(|it| if it.is_ascii_alphanumeric() { it } else { '-' })
+        .collect();
+    format!("{}@nordic-wellness-booker", id)
+}
+
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
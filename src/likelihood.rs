@@ -0,0 +1,45 @@
+use crate::cli::Args;
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::Serialize;
+use std::fmt;
+
+/// A rough guess at how likely a class is to still have a spot when booked.
+/// Purely informational, it never changes what gets booked.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Likelihood {
+    Likely,
+    Competitive,
+    Full,
+}
+
+impl fmt::Display for Likelihood {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Likelihood::Likely => "likely",
+            Likelihood::Competitive => "competitive",
+            Likelihood::Full => "full",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+/// Spots drain as the class gets closer, so the further out a class is the
+/// more free slots it needs to count as likely.
+pub fn estimate(
+    args: &Args,
+    free_slots: i64,
+    start: &DateTime<FixedOffset>,
+    now: &DateTime<Utc>,
+) -> Likelihood {
+    if free_slots <= 0 {
+        return Likelihood::Full;
+    }
+    let days_until = (start.with_timezone(&Utc) - *now).num_days().max(0);
+    let needed = args.likely_free_slots + days_until * args.likely_free_slots_per_day;
+    if free_slots >= needed {
+        Likelihood::Likely
+    } else {
+        Likelihood::Competitive
+    }
+}
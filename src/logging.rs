@@ -0,0 +1,152 @@
+use crate::cli::{Args, LogFormat};
+use crate::outcome::Decision;
+use crate::BookableActivity;
+use chrono::Utc;
+use env_logger::{Builder, Env};
+use log::info;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::sync::RwLock;
+
+const DECISION_TARGET: &str = "decision";
+
+/// Values masked in every log line with `--redact`, each with its replacement
+static REDACTIONS: RwLock<Vec<(String, String)>> = RwLock::new(vec![]);
+
+/// User ids masked wherever they stand as a number of their own
+static USER_IDS: RwLock<Vec<(String, String)>> = RwLock::new(vec![]);
+
+/// Ids with fewer digits only are masked in the shapes they're logged in,
+/// since as numbers of their own they'd also mask dates and counts
+const MIN_STANDALONE_ID_DIGITS: usize = 4;
+
+pub fn init(args: &Args) {
+    let mut builder = Builder::from_env(Env::new().default_filter_or("info"));
+    if args.log_format == LogFormat::Json {
+        builder.format(|buf, record| {
+            // Decisions are already json objects, everything else is wrapped in one
+            if record.target() == DECISION_TARGET {
+                return writeln!(buf, "{}", redact(&record.args().to_string()));
+            }
+            let line = json!({
+                "timestamp": Utc::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": redact(&record.args().to_string()),
+            });
+            writeln!(buf, "{}", line)
+        });
+    } else if args.redact {
+        builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "[{} {:<5} {}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                redact(&record.args().to_string())
+            )
+        });
+    }
+    builder.init();
+}
+
+/// Masks the user names, user ids and notifier tokens in everything logged
+/// from now on with `--redact`. They're replaced by a short hash of the
+/// value, so the lines of a user still belong together.
+pub fn redact_values(args: &Args, activities: &[BookableActivity], secrets: Vec<String>) {
+    if !args.redact {
+        return;
+    }
+    let mut redactions = REDACTIONS.write().unwrap();
+    let mut user_ids = USER_IDS.write().unwrap();
+    for activity in activities {
+        let id = activity.user_id.to_string();
+        let user_id = format!("id-{}", short_hash(&id));
+        if id.len() >= MIN_STANDALONE_ID_DIGITS {
+            user_ids.push((id.clone(), user_id.clone()));
+        }
+        // The timeslot url, the booking form and response, the activity key
+        // and the saved response file name
+        for (before, after) in [
+            ("userId=", ""),
+            ("UserId=", ""),
+            ("\"UserId\":", ""),
+            ("\"UserId\": ", ""),
+            ("", &format!("/{}/", activity.id)),
+            ("-", &format!("-{}-", activity.id)),
+        ] {
+            redactions.push((
+                format!("{}{}{}", before, id, after),
+                format!("{}{}{}", before, user_id, after),
+            ));
+        }
+        let user_name = format!("user-{}", short_hash(&activity.user_name));
+        redactions.push((activity.user_name.clone(), user_name));
+    }
+    let tokens = [
+        &args.telegram_bot_token,
+        &args.telegram_chat_id,
+        &args.notify_webhook_url,
+    ];
+    for secret in tokens.into_iter().flatten().cloned().chain(secrets) {
+        let token = format!("token-{}", short_hash(&secret));
+        redactions.push((secret, token));
+    }
+    // The longest first, so a value containing another is masked whole
+    redactions.retain(|(value, _)| !value.is_empty());
+    redactions.sort_by_key(|(value, _)| std::cmp::Reverse(value.len()));
+    redactions.dedup();
+    user_ids.sort_by_key(|(value, _)| std::cmp::Reverse(value.len()));
+    user_ids.dedup();
+}
+
+/// The text with the values registered by `redact_values` masked
+pub fn redact(text: &str) -> String {
+    let redactions = REDACTIONS.read().unwrap();
+    let mut text = text.to_string();
+    for (value, replacement) in redactions.iter() {
+        if text.contains(value.as_str()) {
+            text = text.replace(value.as_str(), replacement);
+        }
+    }
+    for (id, replacement) in USER_IDS.read().unwrap().iter() {
+        text = replace_standalone(&text, id, replacement);
+    }
+    text
+}
+
+/// Replaces `value` where it isn't part of a longer word or number
+pub fn replace_standalone(text: &str, value: &str, replacement: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find(value) {
+        let end = index + value.len();
+        let before = rest[..index].chars().next_back();
+        let after = rest[end..].chars().next();
+        replaced += &rest[..index];
+        if before.is_some_and(char::is_alphanumeric) || after.is_some_and(char::is_alphanumeric) {
+            replaced += value;
+        } else {
+            replaced += replacement;
+        }
+        rest = &rest[end..];
+    }
+    replaced + rest
+}
+
+fn short_hash(value: &str) -> String {
+    Sha256::digest(value.as_bytes())[..3]
+        .iter()
+        .map(|it| format!("{:02x}", it))
+        .collect()
+}
+
+/// Only json logs get the structured decision, text logs already say the same
+pub fn log_decision(args: &Args, decision: &Decision) {
+    if args.log_format == LogFormat::Json {
+        let line = serde_json::to_string(decision).expect("decisions serialize to json");
+        info!(target: DECISION_TARGET, "{}", line);
+    }
+}
@@ -1,146 +1,498 @@
-use chrono::{DateTime, Datelike, FixedOffset, NaiveDateTime, TimeZone, Utc, Weekday};
-use env_logger::{init_from_env, Env};
-use eyre::{Error, Result};
-use log::{error, info};
-use reqwest::StatusCode;
+use chrono::{DateTime, Datelike, Utc, Weekday};
+use clap::Parser;
+use eyre::Result;
+use log::{debug, error, info, warn};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
+use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct BookingsDto {
-    #[serde(rename = "groupActivities")]
-    group_activities: Vec<GroupActivity>,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct GroupActivity {
-    #[serde(rename = "Id")]
-    id: i64,
-    #[serde(rename = "Name")]
-    name: String,
-    #[serde(rename = "ImageUrl")]
-    image_url: Option<serde_json::Value>,
-    #[serde(rename = "Description")]
-    description: Option<serde_json::Value>,
-    #[serde(rename = "Message")]
-    message: Option<String>,
-    #[serde(rename = "Status")]
-    status: String,
-    #[serde(rename = "StartTime")]
-    start_time: String,
-    #[serde(rename = "EndTime")]
-    end_time: String,
-    #[serde(rename = "Location")]
-    location: String,
-    #[serde(rename = "Instructor")]
-    instructor: String,
-    #[serde(rename = "InstructorId")]
-    instructor_id: i64,
-    #[serde(rename = "FreeSlots")]
-    free_slots: i64,
-    #[serde(rename = "Dropin")]
-    dropin: i64,
-    #[serde(rename = "DropsAmount")]
-    drops_amount: i64,
-    #[serde(rename = "BookingId")]
-    booking_id: Option<serde_json::Value>,
-}
-
-fn get_nw_date(time: &NaiveDateTime) -> String {
-    // create a timezone instance of UTC+2 = Sweden
-    let swe_tz = FixedOffset::east_opt(2 * 3600).expect("Time out of bounds");
-    swe_tz
-        .from_utc_datetime(time)
-        .date_naive()
-        .format("%Y-%m-%d")
-        .to_string()
-}
-
-fn get_bookings_url(user_id: &str, activity_id: &str) -> String {
-    let now = Utc::now().naive_utc();
-    let today = get_nw_date(&now);
-    let in_one_week = get_nw_date(&(now + chrono::Duration::weeks(1)));
-    format!("https://api1.nordicwellness.se/GroupActivity/timeslot?clubIds=1&activities={activity_id}&dates={today}%2C{in_one_week}&time=&employees=&times=09%3A00-11%3A00%2C17%3A00-22%3A00&datespan=true&userId={user_id}")
-}
-
-async fn book_activity(
-    activity_id: u32,
-    user_id: u32,
-) -> Result<reqwest::Response, reqwest::Error> {
-    let url = "https://api1.nordicwellness.se/Booking";
-    let client = reqwest::Client::new();
-    let body_data = (
-        ("ActivityId", activity_id),
-        ("UserId", user_id),
-        ("QueueType", "ordinary"),
-    );
+use crate::api::{get_bookings_url, run_span, Api, BookingForm, BookingsDto, GroupActivity};
+use crate::bookability::{availability, Availability};
+use crate::cli::{Args, ConcurrencyMode, NotifyPolicy, OrderBy};
+use crate::notify::Notifier;
+use crate::outcome::{BookingOutcome, Decision, Report};
+use crate::selection::MatchedIn;
+use crate::state::State;
+use crate::tz::parse_date;
 
-    let body = reqwest::Body::from(serde_urlencoded::to_string(body_data).unwrap());
+mod api;
+mod auth;
+mod bookability;
+mod cli;
+mod clock;
+mod confirm;
+mod conflicts;
+mod hooks;
+mod ics;
+mod likelihood;
+mod logging;
+mod notify;
+mod outcome;
+mod preview;
+mod retry_queue;
+mod selection;
+mod selftest;
+mod session;
+mod signing;
+mod state;
+mod systemd;
+mod tz;
+mod waitlist;
+mod watch;
+mod weekly;
 
-    client
-        .post(url)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(body)
-        .send()
-        .await
-}
+const ACTIVITIES_PATH: &str = "./assets/bookable-activities.json";
 
-fn parse_date(date_str: &str) -> DateTime<Utc> {
-    // This is dates in a swedish tz
-    let date_str = date_str.to_string() + "+02:00";
-    let datetime = DateTime::parse_from_rfc3339(&date_str).unwrap();
-    datetime.with_timezone(&Utc)
+/// Books the activity, polling until a class is booked or the poll times out
+/// with `--retry-until-booked`. Every poll fetches and matches from scratch so
+/// the free slots are always checked against the latest availability.
+async fn attempt_to_book_activity(
+    args: &Args,
+    api: &Api,
+    activity: BookableActivity,
+    decision: &mut Decision,
+) -> Result<BookingOutcome> {
+    let deadline = Instant::now() + Duration::from_secs(args.poll_timeout_minutes * 60);
+    loop {
+        // Another activity of the user may have found their auth dead meanwhile
+        if let Some(reason) = auth::blocked(&activity) {
+            return Ok(BookingOutcome::Blocked { reason });
+        }
+        let outcome = attempt_once(args, api, &activity, decision).await;
+        let is_final = matches!(
+            outcome,
+            Ok(BookingOutcome::Booked { .. }
+                | BookingOutcome::Queued { .. }
+                | BookingOutcome::WouldBook { .. }
+                | BookingOutcome::Skipped { .. }
+                | BookingOutcome::SkippedQuietly { .. }
+                | BookingOutcome::Blocked { .. })
+        );
+        if !args.retry_until_booked || is_final || Instant::now() >= deadline {
+            return outcome;
+        }
+        match &outcome {
+            Ok(outcome) => info!("{} for {}, polling again", outcome, &activity.name),
+            Err(err) => warn!("{}, polling {} again", err, &activity.name),
+        }
+        tokio::time::sleep(Duration::from_secs(args.poll_interval_seconds)).await;
+    }
 }
 
-async fn attempt_to_book_activity(activity: BookableActivity) -> Result<()> {
-    let url = get_bookings_url(&activity.user_id.to_string(), &activity.id);
+async fn attempt_once(
+    args: &Args,
+    api: &Api,
+    activity: &BookableActivity,
+    decision: &mut Decision,
+) -> Result<BookingOutcome> {
+    let now = clock::now();
+    let (from, to) = run_span(args, &now);
+    let url = get_bookings_url(&activity.user_id.to_string(), &activity.id, &from, &to);
     info!(
         "sending request to get activities with id {} for user {}",
         &activity.id, &activity.user_name
     );
     info!("{}", url);
-    let response = reqwest::get(url).await?;
-
-    let dto: BookingsDto = serde_json::from_str(&response.text().await?)?;
-    let body_balance_activity = dto.group_activities.iter().find(|it| {
-        let is_same_name = it
-            .name
-            .to_lowercase()
-            .contains(&activity.name.to_lowercase());
-        let is_correct_day = parse_date(&it.start_time).weekday()
-            == parse_weekday(&activity.day).expect("invalid week day");
-        let is_correct_status = it.status == "Bookable";
-        is_same_name && is_correct_day && is_correct_status
-    });
-    let nw_activity = match body_balance_activity {
-        Some(it) => it,
+    let budget = Duration::from_millis(args.processing_budget_ms);
+    let fetch_started = Instant::now();
+    let mut empty_retries = 0;
+    // A response without any classes right as the schedule publishes is
+    // often followed by a full one a moment later
+    let (mut dto, processing_started) = loop {
+        let text = api.get_group_activities_text(&url).await?;
+        decision.fetch_ms = Some(fetch_started.elapsed().as_millis());
+        let processing_started = Instant::now();
+        let parse = tokio::task::spawn_blocking(move || serde_json::from_str::<BookingsDto>(&text));
+        let dto = match tokio::time::timeout(budget, parse).await {
+            Ok(parsed) => parsed??,
+            Err(_) => return Ok(over_budget(activity, budget)),
+        };
+        if !dto.group_activities.is_empty() || empty_retries >= args.empty_retries {
+            break (dto, processing_started);
+        }
+        empty_retries += 1;
+        info!(
+            "the response for {} has no classes, fetching again ({}/{})",
+            &activity.name, empty_retries, args.empty_retries
+        );
+        tokio::time::sleep(Duration::from_millis(args.empty_retry_delay_ms)).await;
+    };
+    selection::cap_candidates(args, activity, &mut dto.group_activities);
+    conflicts::note_booked(activity, &dto.group_activities);
+    let in_window = |it: &GroupActivity| activity.is_in_booking_window(args, it, &now);
+    let upcoming: Vec<_> = dto
+        .group_activities
+        .iter()
+        .filter(|it| {
+            selection::is_match(activity, it) && !selection::has_passed(activity, it, &now)
+        })
+        .collect();
+    if let Some(minutes) = activity.book_only_within_minutes_of_open {
+        if !upcoming.iter().any(|it| in_window(it)) {
+            let reason = format!("not within {} minutes of a booking opening", minutes);
+            debug!("Skipping {}: {}", &activity.name, reason);
+            return Ok(BookingOutcome::SkippedQuietly { reason });
+        }
+    }
+    let availabilities: Vec<_> = upcoming
+        .iter()
+        .copied()
+        .filter(|it| in_window(it))
+        .map(|it| (it, availability(args, activity, it)))
+        .collect();
+    let candidates: Vec<_> = availabilities
+        .iter()
+        .map(|(it, _)| *it)
+        .filter(|it| bookability::is_candidate(args, activity, it))
+        .collect();
+    decision.candidates = candidates.len();
+    decision.full = availabilities
+        .iter()
+        .filter(|(_, it)| matches!(it, Availability::Full | Availability::TooCrowded))
+        .count();
+    let most_free_slots = availabilities
+        .iter()
+        .filter(|(_, availability)| *availability == Availability::TooCrowded)
+        .map(|(it, _)| it.free_slots)
+        .max();
+    if let (true, Some(most_free_slots)) = (candidates.is_empty(), most_free_slots) {
+        let reason = format!(
+            "only {} free slots, below min_free_slots {}",
+            most_free_slots,
+            activity.min_free_slots.unwrap_or_default()
+        );
+        info!("Not booking {}: {}", &activity.name, reason);
+        return Ok(BookingOutcome::Skipped { reason });
+    }
+    let nw_activity = match selection::select_best(args, activity, candidates) {
+        Some(selection) => {
+            decision.class_id = Some(selection.class.id);
+            decision.reason = Some(selection.reason);
+            let matched = selection::matched_name(activity, selection.class);
+            if let Some((name, MatchedIn::Description)) = matched {
+                info!(
+                    "{} matched {} by its description",
+                    selection.class.name, name
+                );
+            }
+            let matched_name = matched.map(|(name, _)| name);
+            let translated =
+                matched_name.filter(|it| activity.translations.iter().any(|t| t == it));
+            if let Some(translation) = translated {
+                info!(
+                    "{} matched {} by its translation {}",
+                    selection.class.name, &activity.name, translation
+                );
+            } else if let Some(alias) = matched_name.filter(|it| *it != activity.name) {
+                info!(
+                    "{} matched alias {} of {}, consider making it the name if the class was renamed",
+                    selection.class.name, alias, &activity.name
+                );
+            }
+            decision.matched_name = matched_name.map(String::from);
+            decision.matched_in = matched.map(|(_, matched_in)| matched_in);
+            selection.class
+        }
         None => {
+            let not_yet_open = dto.group_activities.iter().find_map(|it| {
+                let opens_at = activity.booking_opens_at(args, it);
+                (selection::is_match(activity, it) && opens_at > now).then_some((it, opens_at))
+            });
+            if let Some((class, opens_at)) = not_yet_open {
+                info!(
+                    "{} at {} isn't open for booking until {}",
+                    class.name,
+                    class.start_time,
+                    opens_at.with_timezone(&tz::CLUB_TZ)
+                );
+                return Ok(BookingOutcome::NotYetOpen {
+                    class: class.name.clone(),
+                    start_time: class.start_time.clone(),
+                    opens_at,
+                });
+            }
             info!(
-                "Unable to find activity with name {} day {} and status {}",
-                &activity.name, &activity.day, "Bookable"
+                "Unable to find a bookable activity with name {} day {}",
+                &activity.name, &activity.day
             );
+            selection::warn_if_misnamed(activity, &dto.group_activities);
             let json = serde_json::to_string_pretty(&dto).unwrap();
             info!("{}", json);
-            return Ok(());
+            return Ok(BookingOutcome::NotFound);
         }
     };
+    if processing_started.elapsed() > budget {
+        return Ok(over_budget(activity, budget));
+    }
+    let booking_started = Instant::now();
+    let outcome = book_class(args, api, activity, nw_activity).await;
+    decision.booking_ms = Some(booking_started.elapsed().as_millis());
+    outcome
+}
+
+fn over_budget(activity: &BookableActivity, budget: Duration) -> BookingOutcome {
+    warn!(
+        "parsing and matching the classes of {} for user {} took longer than {} ms, not booking",
+        &activity.name,
+        &activity.user_name,
+        budget.as_millis()
+    );
+    BookingOutcome::Failed {
+        reason: String::from("processing budget exceeded"),
+    }
+}
+
+async fn run_activity(
+    args: &Args,
+    api: &Api,
+    activity: BookableActivity,
+) -> (BookingOutcome, Option<Decision>) {
     info!(
-        "Found {} starting at time {}. Attempting to book it",
-        nw_activity.name, nw_activity.start_time
+        "checking activity {} for user {}",
+        &activity.name, &activity.user_name
     );
+    let mut decision = Decision::start(activity.key());
+    let outcome = match attempt_to_book_activity(args, api, activity, &mut decision).await {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            error!("{}", err.to_string());
+            BookingOutcome::Failed {
+                reason: err.to_string(),
+            }
+        }
+    };
+    decision.finish(&outcome);
+    if !matches!(outcome, BookingOutcome::SkippedQuietly { .. }) {
+        logging::log_decision(args, &decision);
+    }
+    (outcome, Some(decision))
+}
+
+/// Books the activities one after the other. Once `--group-daily-cap`
+/// activities of a `group` are booked on a day the rest of the user's
+/// activities in that group on that day are skipped.
+async fn book_in_order(
+    args: &Args,
+    api: &Api,
+    activities: &[BookableActivity],
+) -> Vec<(BookingOutcome, Option<Decision>)> {
+    let mut outcomes = vec![];
+    let mut booked: HashMap<(u32, &str, Weekday), Vec<&BookableActivity>> = HashMap::new();
+    let cap = args.group_daily_cap.max(1);
+    for activity in activities {
+        let group = activity.group.as_deref().map(|it| {
+            let day = parse_weekday(&activity.day).expect("invalid week day");
+            (activity.user_id, it, day)
+        });
+        let full_group = group
+            .and_then(|it| booked.get(&it))
+            .filter(|it| it.len() >= cap);
+        let (outcome, decision) = match full_group {
+            Some(booked) => {
+                let names: Vec<_> = booked.iter().map(|it| it.name.as_str()).collect();
+                let reason = format!(
+                    "{} already booked in group {} on {}",
+                    names.join(", "),
+                    activity.group.as_deref().unwrap_or_default(),
+                    &activity.day
+                );
+                (BookingOutcome::Skipped { reason }, None)
+            }
+            None => run_activity(args, api, activity.clone()).await,
+        };
+        let is_booked = matches!(
+            outcome,
+            BookingOutcome::Booked { .. } | BookingOutcome::WouldBook { .. }
+        );
+        if let (Some(group), true) = (group, is_booked) {
+            let booked = booked.entry(group).or_default();
+            booked.push(activity);
+            if booked.len() >= cap {
+                info!(
+                    "booked {} from group {} for user {}, skipping the rest of the group on {}",
+                    &activity.name, group.1, &activity.user_name, &activity.day
+                );
+            }
+        }
+        outcomes.push((outcome, decision));
+    }
+    outcomes
+}
+
+/// Orders the activities by when their next class starts, soonest first. The
+/// date follows from `day` and the time of day from the latest class booked for
+/// the activity in the state file, an activity without one comes after the
+/// others of that day. Ties keep config order.
+fn order_by_start_time(args: &Args, activities: &mut [BookableActivity]) {
+    let state = args.state.as_deref().map(State::load).unwrap_or_default();
+    let today = clock::now()
+        .with_timezone(&tz::CLUB_TZ)
+        .date_naive()
+        .weekday();
+    activities.sort_by_cached_key(|activity| {
+        let day = parse_weekday(&activity.day).expect("invalid week day");
+        let days_ahead = (7 + day.num_days_from_monday() - today.num_days_from_monday()) % 7;
+        let time = state
+            .activities
+            .get(&activity.key())
+            .and_then(|it| it.history.last())
+            .map(|it| parse_date(&it.start_time).time());
+        (days_ahead, time.is_none(), time)
+    });
+    let names: Vec<_> = activities.iter().map(|it| it.key()).collect();
+    info!("booking by start time: {}", names.join(", "));
+}
+
+/// Keeps the activities of a user that share a `group` together, every other
+/// activity is booked on its own
+fn group_activities(activities: Vec<BookableActivity>) -> Vec<Vec<BookableActivity>> {
+    let mut units: Vec<Vec<BookableActivity>> = vec![];
+    for activity in activities {
+        let unit = units.iter_mut().find(|unit| {
+            activity.group.is_some()
+                && unit[0].group == activity.group
+                && unit[0].user_id == activity.user_id
+        });
+        match unit {
+            Some(unit) => unit.push(activity),
+            None => units.push(vec![activity]),
+        }
+    }
+    units
+}
+
+/// All activities of a user in config order, so a user never has two requests in flight
+fn activities_per_user(activities: Vec<BookableActivity>) -> Vec<Vec<BookableActivity>> {
+    let mut users: Vec<Vec<BookableActivity>> = vec![];
+    for activity in activities {
+        match users
+            .iter_mut()
+            .find(|user| user[0].user_id == activity.user_id)
+        {
+            Some(user) => user.push(activity),
+            None => users.push(vec![activity]),
+        }
+    }
+    users
+}
 
-    let response = book_activity(nw_activity.id as u32, activity.user_id).await?;
-    let status = response.status();
-    let text = response.text().await?;
-    if status != StatusCode::OK {
-        let err_msg = format!("code {}: {}", status.as_str(), text,);
-        return Err(Error::msg(err_msg));
+/// Books an already selected class, or only reports it in a dry run
+async fn book_class(
+    args: &Args,
+    api: &Api,
+    activity: &BookableActivity,
+    nw_activity: &GroupActivity,
+) -> Result<BookingOutcome> {
+    if let Some(reason) = auth::blocked(activity) {
+        return Ok(BookingOutcome::Blocked { reason });
+    }
+    // Without a queue type joining the waitlist can't be sent, and an
+    // ordinary booking instead would take the spot the activity must not take
+    let queue_type = match bookability::queues_for(args, activity, nw_activity) {
+        true => match &args.waitlist_queue_type {
+            Some(queue_type) => Some(queue_type.as_str()),
+            None => {
+                warn!(
+                    "{} would join the waitlist of {} but --waitlist-queue-type isn't set, not booking it",
+                    &activity.name, nw_activity.name
+                );
+                return Ok(BookingOutcome::Skipped {
+                    reason: String::from("joining a waitlist needs --waitlist-queue-type"),
+                });
+            }
+        },
+        false => None,
+    };
+    if let Some(reason) = conflicts::claim(args, activity, nw_activity) {
+        return Ok(BookingOutcome::Skipped { reason });
     }
+    // A replay only reproduces decisions, it never books
+    if args.dry_run || args.replay.is_some() {
+        let likelihood = likelihood::estimate(
+            args,
+            nw_activity.free_slots,
+            &parse_date(&nw_activity.start_time),
+            &clock::now(),
+        );
+        let action = match queue_type {
+            Some(_) => "joining its waitlist",
+            None => "booking it",
+        };
+        info!(
+            "Found {} starting at time {} with {} free slots ({}). Not {} in a dry run",
+            nw_activity.name, nw_activity.start_time, nw_activity.free_slots, likelihood, action
+        );
+        return Ok(BookingOutcome::WouldBook {
+            class: nw_activity.name.clone(),
+            start_time: nw_activity.start_time.clone(),
+            end_time: nw_activity.end_time.clone(),
+            likelihood,
+        });
+    }
+    if confirm::already_booked(args, api, activity, nw_activity).await {
+        info!(
+            "{} at {} is already booked for user {}, not booking it again",
+            nw_activity.name, nw_activity.start_time, &activity.user_name
+        );
+        return Ok(BookingOutcome::Skipped {
+            reason: String::from("already booked"),
+        });
+    }
+    info!(
+        "Found {} starting at time {}. Attempting to {}",
+        nw_activity.name,
+        nw_activity.start_time,
+        if queue_type.is_some() {
+            "join its waitlist"
+        } else {
+            "book it"
+        }
+    );
+
+    let form = BookingForm {
+        activity_id: nw_activity.id as u32,
+        user_id: activity.user_id,
+        queue_type: queue_type.unwrap_or("ordinary"),
+    };
+    let text = match api.book_activity(&form).await {
+        Ok(text) => text,
+        Err(err) => {
+            conflicts::release(activity, nw_activity);
+            if let Some(reason) = auth::block_on(args, activity, &err) {
+                return Ok(BookingOutcome::Blocked { reason });
+            }
+            return Err(err);
+        }
+    };
     info!("{}", text);
+    if let Some(dir) = &args.save_responses {
+        save_response(dir, activity, &text);
+    }
+    let class = nw_activity.name.clone();
+    let start_time = nw_activity.start_time.clone();
+    let end_time = nw_activity.end_time.clone();
+    if queue_type.is_some() {
+        info!("Joined the waitlist of {}", nw_activity.name);
+        return Ok(BookingOutcome::Queued {
+            class,
+            start_time,
+            end_time,
+        });
+    }
     info!("Booked {}", nw_activity.name);
-    Ok(())
+    let confirmation = confirm::confirm(args, api, activity, nw_activity).await;
+    Ok(BookingOutcome::Booked {
+        class,
+        start_time,
+        end_time,
+        confirmation,
+    })
 }
 
 fn read_json<T: DeserializeOwned>(path: &str) -> T {
@@ -152,7 +504,7 @@ fn read_json<T: DeserializeOwned>(path: &str) -> T {
     serde_json::from_str::<T>(&contents).expect("unable to deserialize json")
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 struct BookableActivity {
     name: String,
     id: String,
@@ -160,6 +512,196 @@ struct BookableActivity {
     day: String,
     user_name: String,
     disabled: Option<bool>,
+    /// How much to prefer classes with more free slots over other matches
+    free_slots_weight: Option<f64>,
+    /// Not sent, the booking API has no known field for it
+    note: Option<String>,
+    /// Why the activity is configured, only shown in reports
+    notes: Option<String>,
+    /// Overrides `--booking-open-days-before` for this activity
+    booking_open_days_before: Option<i64>,
+    /// Never book a class with fewer free slots than this
+    min_free_slots: Option<i64>,
+    /// Only the first bookable activity of a group is booked for a user
+    group: Option<String>,
+    /// Whether a class stops being bookable once it starts or once it ends
+    exclude_after: Option<selection::ExcludeAfter>,
+    /// Only attempt the activity this close to a class opening for booking
+    book_only_within_minutes_of_open: Option<i64>,
+    /// Other names the class is matched by, e.g. after the club renamed it
+    aliases: Option<Vec<String>>,
+    /// Only match classes whose response fields are at least these numbers, e.g. a capacity
+    min_fields: Option<HashMap<String, f64>>,
+    /// Fetch and match the class again when its booking opens in watch mode instead of booking the scheduled id
+    resolve_on_fire: Option<bool>,
+    /// Also look for the name in the class description when the class name doesn't have it
+    match_description: Option<bool>,
+    /// Only match classes whose response fields equal one of these values, e.g. a category id
+    match_fields: Option<HashMap<String, selection::FieldValues>>,
+    /// Overrides `--max-candidates` for this activity
+    max_candidates: Option<usize>,
+    /// Skip the activity after more no-shows than this within `--no-show-window-days`
+    max_no_shows: Option<usize>,
+    /// Only match classes taught by one of these instructors
+    instructors: Option<Vec<String>>,
+    /// Never match classes taught by any of these instructors
+    exclude_instructors: Option<Vec<String>>,
+    /// Take an ordinary spot, join the waitlist or either, `book_only` when unset
+    booking_preference: Option<bookability::BookingPreference>,
+    /// Watch the full classes of this activity with `--monitor-waitlist`
+    monitor_waitlist: Option<bool>,
+    /// The name and aliases in the other language, from `--translations`
+    #[serde(skip)]
+    translations: Vec<String>,
+}
+
+/// Keeps the raw body of a successful booking for inspection, named
+/// `<timestamp>-<user_id>-<activity id>-<name>.json`
+fn save_response(dir: &Path, activity: &BookableActivity, text: &str) {
+    let name: String = activity
+        .name
+        .chars()
+        .map(|it| if it.is_alphanumeric() { it } else { '_' })
+        .collect();
+    let path = dir.join(format!(
+        "{}-{}-{}-{}.json",
+        Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+        activity.user_id,
+        activity.id,
+        name
+    ));
+    let result = fs::create_dir_all(dir).and_then(|_| fs::write(&path, text));
+    match result {
+        Ok(()) => info!("saved booking response to {}", path.display()),
+        Err(err) => warn!(
+            "unable to save booking response to {}: {}",
+            path.display(),
+            err
+        ),
+    }
+}
+
+impl BookableActivity {
+    /// When booking of one of this activity's classes opens, as stated by the
+    /// club when it is and otherwise computed from the lead time
+    fn booking_opens_at(&self, args: &Args, class: &GroupActivity) -> DateTime<Utc> {
+        if let Some(opens_at) = bookability::bookable_from(class) {
+            return opens_at;
+        }
+        let days_before = self
+            .booking_open_days_before
+            .unwrap_or(args.booking_open_days_before);
+        tz::booking_opens_at(&parse_date(&class.start_time), days_before)
+    }
+
+    /// Whether the class opens for booking within `book_only_within_minutes_of_open`
+    /// of now, always true without it
+    fn is_in_booking_window(
+        &self,
+        args: &Args,
+        class: &GroupActivity,
+        now: &DateTime<Utc>,
+    ) -> bool {
+        self.book_only_within_minutes_of_open.is_none_or(|minutes| {
+            let from_open = (self.booking_opens_at(args, class) - *now).num_seconds();
+            from_open.abs() <= minutes * 60
+        })
+    }
+
+    /// Identifies the activity across runs
+    fn key(&self) -> String {
+        format!("{}/{}/{}/{}", self.user_id, self.id, self.day, self.name)
+    }
+
+    fn report(&self, outcome: BookingOutcome) -> Report {
+        Report {
+            key: self.key(),
+            activity: self.name.clone(),
+            user_name: self.user_name.clone(),
+            notes: self.notes.clone(),
+            outcome,
+            decision: None,
+        }
+    }
+}
+
+/// An activity that keeps finding nothing in an old config has most likely
+/// been discontinued or renamed by the club
+fn warn_if_stale_config(state: &State, max_age_days: i64) {
+    let modified = match fs::metadata(ACTIVITIES_PATH).and_then(|it| it.modified()) {
+        Ok(modified) => DateTime::<Utc>::from(modified),
+        Err(err) => {
+            warn!("unable to read the age of {}: {}", ACTIVITIES_PATH, err);
+            return;
+        }
+    };
+    let age_days = (Utc::now() - modified).num_days();
+    if age_days < max_age_days {
+        return;
+    }
+    for (key, activity) in &state.activities {
+        if activity.not_found_streak > 0 {
+            warn!(
+                "{} hasn't changed in {} days and {} found no class the last {} runs, review whether it still exists",
+                ACTIVITIES_PATH, age_days, key, activity.not_found_streak
+            );
+        }
+    }
+}
+
+/// The week days a `day` of `weekdays`, `weekends` or `daily` stands for
+fn parse_day_set(value: &str) -> Option<&'static [&'static str]> {
+    const WEEKDAYS: &[&str] = &["monday", "tuesday", "wednesday", "thursday", "friday"];
+    const WEEKENDS: &[&str] = &["saturday", "sunday"];
+    const DAILY: &[&str] = &[
+        "monday",
+        "tuesday",
+        "wednesday",
+        "thursday",
+        "friday",
+        "saturday",
+        "sunday",
+    ];
+    match value.to_lowercase().as_str() {
+        "weekdays" => Some(WEEKDAYS),
+        "weekends" => Some(WEEKENDS),
+        "daily" => Some(DAILY),
+        _ => None,
+    }
+}
+
+/// Turns an activity for several days into one activity per day, each booked
+/// on its own like any other activity. Fails on a day that isn't valid.
+fn expand_days(activities: Vec<BookableActivity>) -> Result<Vec<BookableActivity>> {
+    let mut expanded = vec![];
+    for activity in activities {
+        match parse_day_set(&activity.day) {
+            Some(days) => expanded.extend(days.iter().map(|day| BookableActivity {
+                day: day.to_string(),
+                ..activity.clone()
+            })),
+            None if parse_weekday(&activity.day).is_some() => expanded.push(activity),
+            None => {
+                return Err(eyre::Error::msg(format!(
+                "invalid day {} of activity {}, expected a week day, weekdays, weekends or daily",
+                activity.day, activity.name
+            )))
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// The activities as json that reads back as the same config, leaving out
+/// the options that aren't set
+fn dump_config(activities: &[BookableActivity]) -> Result<String> {
+    let mut value = serde_json::to_value(activities)?;
+    for activity in value.as_array_mut().into_iter().flatten() {
+        if let Some(fields) = activity.as_object_mut() {
+            fields.retain(|_, it| !it.is_null());
+        }
+    }
+    Ok(serde_json::to_string_pretty(&value)?)
 }
 
 fn parse_weekday(value: &str) -> Option<Weekday> {
@@ -177,12 +719,58 @@ fn parse_weekday(value: &str) -> Option<Weekday> {
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
-    init_from_env(Env::new().default_filter_or("info"));
-    let bookable_activities: Vec<BookableActivity> = read_json("./assets/bookable-activities.json");
+    let args = Arc::new(Args::parse());
+    logging::init(&args);
+    clock::init(&args)?;
+    tz::log_mechanism(args.max_tz_age_years);
+    if args.stats {
+        let path = args.state.as_ref().expect("--stats requires --state");
+        println!("{}", State::load(path).stats_text());
+        return Ok(());
+    }
+    if let Some(monday) = args.weekly_report {
+        let path = args
+            .state
+            .as_ref()
+            .expect("--weekly-report requires --state");
+        let state = State::load(path);
+        println!("{}", weekly::text(&state, monday, args.report_format));
+        return Ok(());
+    }
+    let notifier = Notifier::from_args(&args)?;
+    if args.check_notifiers {
+        if !notifier.check().await {
+            return Err(eyre::Error::msg("some notifiers failed"));
+        }
+        return Ok(());
+    }
+    let api = Arc::new(Api::from_args(&args)?);
+    if let Some(dir) = &args.save_responses {
+        warn!(
+            "saving booking responses to {} unredacted, they may contain personal information",
+            dir.display()
+        );
+    }
+    let bookable_activities: Vec<BookableActivity> = read_json(ACTIVITIES_PATH);
+    let mut bookable_activities = expand_days(bookable_activities)?;
+    if let Some(path) = &args.translations {
+        let translations: selection::Translations = read_json(&path.to_string_lossy());
+        for activity in &mut bookable_activities {
+            activity.translations = selection::translate(&translations, activity);
+        }
+    }
+    logging::redact_values(&args, &bookable_activities, notifier.secrets());
+    if args.dump_config {
+        println!("{}", dump_config(&bookable_activities)?);
+        return Ok(());
+    }
+    if args.selftest {
+        return selftest::run(&args, &api, &bookable_activities, &notifier).await;
+    }
     let all_bookable_activities = bookable_activities.len();
     info!("found {} bookable activities", all_bookable_activities);
     let mut handles = vec![];
-    let bookable_activities: Vec<BookableActivity> = bookable_activities
+    let mut bookable_activities: Vec<BookableActivity> = bookable_activities
         .into_iter()
         .filter(|it| !it.disabled.unwrap_or(false))
         .collect();
@@ -190,27 +778,211 @@ async fn main() -> Result<()> {
         "removed {} disabled activities",
         all_bookable_activities - bookable_activities.len()
     );
+    if args.preview {
+        return preview::run(&args, &api, &bookable_activities).await;
+    }
+    if let Some(path) = &args.plan {
+        return preview::write_plan(&args, &api, &bookable_activities, path).await;
+    }
+    if args.watch || args.rolling {
+        return watch::run(args, api, bookable_activities, notifier).await;
+    }
+    if args.monitor_waitlist {
+        return waitlist::run(args, api, bookable_activities, notifier).await;
+    }
+    if let (Some(path), Some(minutes), false) =
+        (&args.state, args.min_run_interval_minutes, args.force)
+    {
+        if let Some(last_run) = State::load(path).last_run {
+            let since = clock::now() - last_run;
+            // A last run in the future is a bad clock, not a recent run
+            if since >= chrono::Duration::zero() && since < chrono::Duration::minutes(minutes) {
+                info!(
+                    "last run was {} minutes ago, less than --min-run-interval-minutes {}. Exiting, pass --force to run anyway",
+                    since.num_minutes(),
+                    minutes
+                );
+                return Ok(());
+            }
+        }
+    }
     info!("trying to book {} activities", bookable_activities.len());
 
-    for activity in bookable_activities {
-        info!(
-            "checking activity {} for user {}",
-            &activity.name, &activity.user_name
-        );
+    for activity in &bookable_activities {
+        if activity.note.is_some() {
+            warn!(
+                "ignoring note of activity {} for user {}, the booking api doesn't accept notes",
+                &activity.name, &activity.user_name
+            );
+        }
+    }
+
+    // Reports of activities that are done before any request is sent
+    let mut reports = vec![];
+    if let Some(path) = &args.state {
+        let state = State::load(path);
+        let since = clock::now() - chrono::Duration::days(args.no_show_window_days);
+        let (skipped, rest): (Vec<_>, Vec<_>) =
+            bookable_activities.into_iter().partition(|activity| {
+                activity
+                    .max_no_shows
+                    .is_some_and(|max| state.no_shows_since(&activity.key(), &since) > max)
+            });
+        for activity in skipped {
+            info!(
+                "skipping {} for user {}, more than {} no-shows in the last {} days",
+                &activity.name,
+                &activity.user_name,
+                activity.max_no_shows.unwrap_or_default(),
+                args.no_show_window_days
+            );
+            reports.push(activity.report(BookingOutcome::Skipped {
+                reason: String::from("repeated no-shows"),
+            }));
+        }
+        bookable_activities = rest;
+    }
+
+    let semaphore = match args.concurrency_mode {
+        ConcurrencyMode::Global => Some(Arc::new(Semaphore::new(args.max_concurrency.max(1)))),
+        _ => None,
+    };
+    if args.order_by == OrderBy::StartTime {
+        order_by_start_time(&args, &mut bookable_activities);
+    }
+    let units = match args.concurrency_mode {
+        ConcurrencyMode::PerUser => activities_per_user(bookable_activities),
+        _ => group_activities(bookable_activities),
+    };
+    for unit in units {
+        let args = args.clone();
+        let api = api.clone();
+        let semaphore = semaphore.clone();
+        let task_unit = unit.clone();
         let handle = tokio::task::spawn(async move {
-            match attempt_to_book_activity(activity).await {
-                Ok(()) => (),
-                Err(err) => error!("{}", err.to_string()),
-            }
+            let _permit = match &semaphore {
+                Some(semaphore) => Some(
+                    semaphore
+                        .acquire()
+                        .await
+                        .expect("semaphore is never closed"),
+                ),
+                None => None,
+            };
+            book_in_order(&args, &api, &task_unit).await
         });
-        handles.push(handle);
+        handles.push((unit, handle));
     }
 
-    for handle in handles {
-        match handle.await {
-            Ok(_) => (),
-            Err(e) => error!("{}", e.to_string()),
+    for (unit, handle) in handles {
+        let outcomes = match handle.await {
+            Ok(outcomes) => outcomes,
+            Err(e) => {
+                error!("{}", e.to_string());
+                let outcome = BookingOutcome::Failed {
+                    reason: e.to_string(),
+                };
+                vec![(outcome, None); unit.len()]
+            }
         };
+        for (activity, (outcome, decision)) in unit.iter().zip(outcomes) {
+            if let BookingOutcome::SkippedQuietly { .. } = outcome {
+                continue;
+            }
+            let mut report = activity.report(outcome);
+            report.decision = decision;
+            api.record_decision(&report);
+            reports.push(report);
+        }
+    }
+
+    let failed = reports.iter().any(|it| {
+        matches!(
+            it.outcome,
+            BookingOutcome::Failed { .. } | BookingOutcome::Blocked { .. }
+        )
+    });
+    let should_notify = |changed: bool| match args.notify_policy {
+        NotifyPolicy::Always => true,
+        NotifyPolicy::OnChange => failed || changed,
+        NotifyPolicy::OnFailure => failed,
+    };
+    // Without a state file to compare with every run counts as a change
+    let mut changed = true;
+    let mut duplicate = false;
+    if let Some(path) = &args.state {
+        let mut state = State::load(path);
+        let now = clock::now();
+        changed = state.outcomes_changed(&reports);
+        state.record(&reports, now);
+        // A run where every activity was skipped quietly doesn't count
+        if !reports.is_empty() {
+            state.record_outcomes(&reports);
+        }
+        if !reports.is_empty() && should_notify(changed) && args.notify_dedup_minutes > 0 {
+            let window = chrono::Duration::minutes(args.notify_dedup_minutes);
+            duplicate = !state.record_notification(&reports, now, window);
+        }
+        if let Some(days) = args.stale_config_days {
+            warn_if_stale_config(&state, days);
+        }
+        // Nothing was booked, and a time from --now must never outlive the run
+        if args.dry_run || args.replay.is_some() || args.now.is_some() {
+            info!(
+                "not saving state to {} for a run that can't book",
+                path.display()
+            );
+        } else if let Err(err) = state.save(path) {
+            error!("unable to save state to {}: {}", path.display(), err);
+        }
+    }
+    if reports.is_empty() {
+        info!("not notifying, no activity has anything to report");
+    } else if duplicate {
+        info!(
+            "not notifying, the same summary was sent within the last {} minutes",
+            args.notify_dedup_minutes
+        );
+    } else if should_notify(changed) {
+        notifier.notify(&reports).await;
+    } else {
+        info!(
+            "not notifying, {}",
+            if changed {
+                "nothing failed"
+            } else {
+                "nothing changed since the last run"
+            }
+        );
+    }
+    hooks::run(&args, &reports).await;
+    if let Some(path) = &args.ics {
+        match ics::write(path, &reports) {
+            Ok(()) => info!("wrote calendar events to {}", path.display()),
+            Err(err) => error!(
+                "unable to write calendar events to {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    if args.oneline {
+        println!(
+            "{}",
+            logging::redact(&outcome::oneline_text(&reports, &clock::now()))
+        );
+    }
+    let exit_on = reports
+        .iter()
+        .filter(|it| args.exit_on.iter().any(|kind| kind == it.outcome.kind()))
+        .count();
+    if exit_on > 0 {
+        info!(
+            "exiting with code 1, {} activities ended {}",
+            exit_on,
+            args.exit_on.join(" or ")
+        );
+        std::process::exit(1);
     }
     Ok(())
 }
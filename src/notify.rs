@@ -0,0 +1,238 @@
+use crate::cli::Args;
+use crate::logging::redact;
+use crate::outcome::{summary_text, BookingOutcome, Report};
+use eyre::{Error, Result, WrapErr};
+#[cfg(feature = "desktop-notify")]
+use log::debug;
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::fs;
+use std::time::Duration;
+
+#[derive(Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+enum Backend {
+    Telegram { token: String, chat_id: String },
+    Webhook { url: String },
+}
+
+/// An entry of the `--notifiers` file
+#[derive(Deserialize)]
+struct Route {
+    #[serde(flatten)]
+    backend: Backend,
+    /// Outcome kinds sent to the backend, every outcome when unset
+    on: Option<Vec<String>>,
+}
+
+impl Route {
+    fn accepts(&self, report: &Report) -> bool {
+        self.on
+            .as_ref()
+            .is_none_or(|on| on.iter().any(|it| it == report.outcome.kind()))
+    }
+}
+
+impl Backend {
+    fn name(&self) -> &'static str {
+        match self {
+            Backend::Telegram { .. } => "telegram",
+            Backend::Webhook { .. } => "webhook",
+        }
+    }
+
+    /// Every payload is sent through `redact`, only the url keeps its secrets
+    async fn send(&self, client: &reqwest::Client, text: &str, reports: &[Report]) -> Result<()> {
+        let request = match self {
+            Backend::Telegram { token, chat_id } => client
+                .post(format!("https://api.telegram.org/bot{token}/sendMessage"))
+                .json(&json!({ "chat_id": chat_id, "text": redact(text) })),
+            Backend::Webhook { url } => client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(redact(
+                    &json!({ "text": text, "reports": reports }).to_string(),
+                )),
+        };
+        // The url of a telegram request holds the bot token, so it's left out of errors
+        let response = request.send().await.map_err(|it| it.without_url())?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.map_err(|it| it.without_url())?;
+            return Err(Error::msg(format!("code {}: {}", status.as_str(), text)));
+        }
+        Ok(())
+    }
+}
+
+const TEST_MESSAGE: &str = "Nordic Wellness booker\nTest message, notifications reach you";
+
+/// Sends the run summary to every configured backend. Delivery failures are
+/// logged but never fail the run.
+pub struct Notifier {
+    routes: Vec<Route>,
+    client: reqwest::Client,
+    attempts: u32,
+    delay: Duration,
+    desktop: bool,
+}
+
+impl Notifier {
+    /// Fails when the `--notifiers` file can't be read or filters on an
+    /// outcome that doesn't exist
+    pub fn from_args(args: &Args) -> Result<Notifier> {
+        let mut backends = vec![];
+        match (&args.telegram_bot_token, &args.telegram_chat_id) {
+            (Some(token), Some(chat_id)) => backends.push(Backend::Telegram {
+                token: token.clone(),
+                chat_id: chat_id.clone(),
+            }),
+            (None, None) => (),
+            _ => warn!("telegram needs both a bot token and a chat id, skipping it"),
+        }
+        if let Some(url) = &args.notify_webhook_url {
+            backends.push(Backend::Webhook { url: url.clone() });
+        }
+        let mut routes: Vec<_> = backends
+            .into_iter()
+            .map(|backend| Route { backend, on: None })
+            .collect();
+        if let Some(path) = &args.notifiers {
+            let contents = fs::read_to_string(path)
+                .wrap_err_with(|| format!("unable to read notifiers {}", path.display()))?;
+            let configured: Vec<Route> = serde_json::from_str(&contents)
+                .wrap_err_with(|| format!("invalid notifiers {}", path.display()))?;
+            for route in &configured {
+                let unknown = route
+                    .on
+                    .iter()
+                    .flatten()
+                    .find(|it| !BookingOutcome::KINDS.contains(&it.as_str()));
+                if let Some(unknown) = unknown {
+                    return Err(Error::msg(format!(
+                        "unknown outcome {} in on of {} notifier, expected one of {}",
+                        unknown,
+                        route.backend.name(),
+                        BookingOutcome::KINDS.join(", ")
+                    )));
+                }
+            }
+            routes.extend(configured);
+        }
+        if args.desktop_notify && !cfg!(feature = "desktop-notify") {
+            warn!("--desktop-notify needs a build with the desktop-notify feature, skipping it");
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(args.notify_timeout_seconds))
+            .build()?;
+        Ok(Notifier {
+            routes,
+            client,
+            attempts: args.notify_retry_attempts.max(1),
+            delay: Duration::from_millis(args.notify_retry_delay_ms),
+            desktop: args.desktop_notify && cfg!(feature = "desktop-notify"),
+        })
+    }
+
+    /// Tokens, chat ids and urls of the configured backends, for `--redact`
+    pub fn secrets(&self) -> Vec<String> {
+        self.routes
+            .iter()
+            .flat_map(|it| match &it.backend {
+                Backend::Telegram { token, chat_id } => vec![token.clone(), chat_id.clone()],
+                Backend::Webhook { url } => vec![url.clone()],
+            })
+            .collect()
+    }
+
+    pub async fn notify(&self, reports: &[Report]) {
+        if self.desktop {
+            notify_desktop(reports);
+        }
+        for route in &self.routes {
+            let reports: Vec<_> = reports
+                .iter()
+                .filter(|it| route.accepts(it))
+                .cloned()
+                .collect();
+            if reports.is_empty() {
+                continue;
+            }
+            let backend = &route.backend;
+            for attempt in 1..=self.attempts {
+                match backend
+                    .send(&self.client, &summary_text(&reports), &reports)
+                    .await
+                {
+                    Ok(()) => {
+                        info!("sent run summary with {}", backend.name());
+                        break;
+                    }
+                    Err(err) => warn!(
+                        "unable to notify with {} (attempt {}/{}): {}",
+                        backend.name(),
+                        attempt,
+                        self.attempts,
+                        err
+                    ),
+                }
+                if attempt < self.attempts {
+                    tokio::time::sleep(self.delay).await;
+                }
+            }
+        }
+    }
+
+    /// Sends a test message with every configured backend, once and without
+    /// retries, and logs whether each got through. Returns whether all did.
+    pub async fn check(&self) -> bool {
+        if self.routes.is_empty() && !self.desktop {
+            warn!("no notifiers are configured");
+        }
+        if self.desktop {
+            notify_desktop_text(TEST_MESSAGE);
+        }
+        let mut ok = true;
+        for (index, route) in self.routes.iter().enumerate() {
+            let backend = &route.backend;
+            match backend.send(&self.client, TEST_MESSAGE, &[]).await {
+                Ok(()) => info!("notifier {} ({}) works", index + 1, backend.name()),
+                Err(err) => {
+                    error!(
+                        "notifier {} ({}) failed: {}",
+                        index + 1,
+                        backend.name(),
+                        err
+                    );
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+}
+
+/// Shows the summary as a desktop notification. Without a desktop, e.g. on a
+/// server without a session bus, there's nobody to show it to so it's only
+/// logged at debug.
+fn notify_desktop(reports: &[Report]) {
+    notify_desktop_text(&summary_text(reports));
+}
+
+#[cfg(feature = "desktop-notify")]
+fn notify_desktop_text(text: &str) {
+    let text = redact(text);
+    let (summary, body) = text.split_once('\n').unwrap_or((&text, ""));
+    match notify_rust::Notification::new()
+        .summary(summary)
+        .body(body)
+        .show()
+    {
+        Ok(_) => info!("showed a desktop notification"),
+        Err(err) => debug!("unable to show a desktop notification: {}", err),
+    }
+}
+
+#[cfg(not(feature = "desktop-notify"))]
+fn notify_desktop_text(_text: &str) {}
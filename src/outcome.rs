@@ -0,0 +1,241 @@
+use crate::clock;
+use crate::confirm::Confirmation;
+use crate::likelihood::Likelihood;
+use crate::selection::{MatchedIn, Reason};
+use crate::tz::{get_nw_date, CLUB_TZ};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt;
+use std::time::Instant;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum BookingOutcome {
+    Booked {
+        class: String,
+        start_time: String,
+        end_time: String,
+        /// Whether the booking was seen afterwards, with `--confirm-polls`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        confirmation: Option<Confirmation>,
+    },
+    /// Joined the waitlist of a class
+    Queued {
+        class: String,
+        start_time: String,
+        end_time: String,
+    },
+    WouldBook {
+        class: String,
+        start_time: String,
+        end_time: String,
+        likelihood: Likelihood,
+    },
+    NotYetOpen {
+        class: String,
+        start_time: String,
+        opens_at: DateTime<Utc>,
+    },
+    NotFound,
+    Skipped {
+        reason: String,
+    },
+    /// Skipped without a report, so frequent runs that have nothing to do
+    /// stay out of the summary, notifications and state
+    #[serde(rename = "skipped")]
+    SkippedQuietly {
+        reason: String,
+    },
+    Failed {
+        reason: String,
+    },
+    /// Not attempted since the user's auth failed earlier in the run
+    Blocked {
+        reason: String,
+    },
+}
+
+impl BookingOutcome {
+    /// Every possible `kind`
+    pub const KINDS: [&'static str; 8] = [
+        "booked",
+        "queued",
+        "would_book",
+        "not_yet_open",
+        "not_found",
+        "skipped",
+        "failed",
+        "blocked",
+    ];
+
+    /// The serialized `outcome` tag
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BookingOutcome::Booked { .. } => "booked",
+            BookingOutcome::Queued { .. } => "queued",
+            BookingOutcome::WouldBook { .. } => "would_book",
+            BookingOutcome::NotYetOpen { .. } => "not_yet_open",
+            BookingOutcome::NotFound => "not_found",
+            BookingOutcome::Skipped { .. } | BookingOutcome::SkippedQuietly { .. } => "skipped",
+            BookingOutcome::Failed { .. } => "failed",
+            BookingOutcome::Blocked { .. } => "blocked",
+        }
+    }
+}
+
+impl fmt::Display for BookingOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BookingOutcome::Booked {
+                class,
+                start_time,
+                confirmation,
+                ..
+            } => {
+                write!(f, "booked {} at {}", class, start_time)?;
+                match confirmation {
+                    Some(confirmation) => write!(f, " ({})", confirmation),
+                    None => Ok(()),
+                }
+            }
+            BookingOutcome::Queued {
+                class, start_time, ..
+            } => {
+                write!(f, "joined the waitlist of {} at {}", class, start_time)
+            }
+            BookingOutcome::WouldBook {
+                class,
+                start_time,
+                likelihood,
+                ..
+            } => write!(f, "would book {} at {} ({})", class, start_time, likelihood),
+            BookingOutcome::NotYetOpen {
+                class,
+                start_time,
+                opens_at,
+            } => write!(
+                f,
+                "{} at {} opens for booking {}",
+                class,
+                start_time,
+                opens_at.with_timezone(&CLUB_TZ).format("%Y-%m-%d %H:%M")
+            ),
+            BookingOutcome::NotFound => write!(f, "no bookable class found"),
+            BookingOutcome::Skipped { reason } | BookingOutcome::SkippedQuietly { reason } => {
+                write!(f, "skipped: {}", reason)
+            }
+            BookingOutcome::Failed { reason } => write!(f, "failed: {}", reason),
+            BookingOutcome::Blocked { reason } => write!(f, "blocked: {}", reason),
+        }
+    }
+}
+
+/// The outcome of a single configured activity
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub key: String,
+    pub activity: String,
+    pub user_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(flatten)]
+    pub outcome: BookingOutcome,
+    /// How the class was picked, when the activity was attempted
+    #[serde(skip)]
+    pub decision: Option<Decision>,
+}
+
+pub fn summary_text(reports: &[Report]) -> String {
+    let mut text = String::from("Nordic Wellness booker");
+    for report in reports {
+        text += &format!(
+            "\n{} ({}): {}",
+            report.activity, report.user_name, report.outcome
+        );
+        if let Some(notes) = &report.notes {
+            text += &format!(" [{}]", notes);
+        }
+    }
+    text
+}
+
+/// The run on a single line, e.g. `NW 2024-03-05: 4 booked, 1 not found, 0 failed`.
+/// Booked, not found and failed are always counted, any other outcome only
+/// when it happened.
+pub fn oneline_text(reports: &[Report], now: &DateTime<Utc>) -> String {
+    let count = |kinds: &[&str]| {
+        reports
+            .iter()
+            .filter(|it| kinds.contains(&it.outcome.kind()))
+            .count()
+    };
+    let mut counts = vec![
+        format!("{} booked", count(&["booked"])),
+        format!("{} not found", count(&["not_found"])),
+        format!("{} failed", count(&["failed"])),
+    ];
+    for (kind, label) in [
+        ("queued", "queued"),
+        ("would_book", "would book"),
+        ("not_yet_open", "not yet open"),
+        ("skipped", "skipped"),
+        ("blocked", "blocked"),
+    ] {
+        let count = count(&[kind]);
+        if count > 0 {
+            counts.push(format!("{} {}", count, label));
+        }
+    }
+    format!("NW {}: {}", get_nw_date(now), counts.join(", "))
+}
+
+/// How an activity's attempt went, logged as a single json line. The field
+/// names are relied on by log tooling, see the README before changing them.
+#[derive(Serialize, Debug, Clone)]
+pub struct Decision {
+    pub event: &'static str,
+    pub timestamp: DateTime<Utc>,
+    pub activity_key: String,
+    pub class_id: Option<i64>,
+    pub candidates: usize,
+    pub full: usize,
+    pub reason: Option<Reason>,
+    pub matched_name: Option<String>,
+    pub matched_in: Option<MatchedIn>,
+    pub outcome: &'static str,
+    pub fetch_ms: Option<u128>,
+    pub booking_ms: Option<u128>,
+    pub total_ms: u128,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl Decision {
+    pub fn start(activity_key: String) -> Decision {
+        Decision {
+            event: "booking_decision",
+            timestamp: clock::now(),
+            activity_key,
+            class_id: None,
+            candidates: 0,
+            full: 0,
+            reason: None,
+            matched_name: None,
+            matched_in: None,
+            outcome: "",
+            fetch_ms: None,
+            booking_ms: None,
+            total_ms: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn elapsed_ms(&self) -> u128 {
+        self.started.elapsed().as_millis()
+    }
+
+    pub fn finish(&mut self, outcome: &BookingOutcome) {
+        self.outcome = outcome.kind();
+        self.total_ms = self.elapsed_ms();
+    }
+}
@@ -0,0 +1,179 @@
+//! `--preview` lists the class every enabled activity would book for each
+//! day of the coming week, including classes that aren't open for booking
+//! yet. `--plan` writes the same picks to a file meant to be diffed and
+//! committed. Both only fetch timeslots and never book.
+use crate::api::{get_bookings_url, run_span, Api, GroupActivity};
+use crate::bookability::{self, availability, Availability};
+use crate::cli::Args;
+use crate::tz::{parse_date, CLUB_TZ};
+use crate::{clock, selection, BookableActivity};
+use eyre::{eyre, Result};
+use log::{error, info};
+use std::fs;
+use std::path::Path;
+
+pub async fn run(args: &Args, api: &Api, activities: &[BookableActivity]) -> Result<()> {
+    let mut rows = vec![];
+    for activity in activities {
+        match plan(args, api, activity).await {
+            Ok(classes) if classes.is_empty() => rows.push(format!(
+                "{:<12} {:<24} {}",
+                activity.user_name,
+                activity.name,
+                match activity.book_only_within_minutes_of_open {
+                    Some(minutes) => format!("no class opens within {} minutes", minutes),
+                    None => String::from("no matching class this week"),
+                }
+            )),
+            Ok(classes) => rows.extend(classes.iter().map(|class| row(args, activity, class))),
+            Err(err) => error!(
+                "unable to preview {} for user {}: {}",
+                &activity.name, &activity.user_name, err
+            ),
+        }
+    }
+    println!(
+        "{:<12} {:<24} {:<24} {:<17} {:>5}  status",
+        "user", "activity", "class", "start", "slots"
+    );
+    for row in rows {
+        println!("{}", row);
+    }
+    Ok(())
+}
+
+/// Writes one line per activity and class it would book, without anything
+/// that changes from run to run like free slots or when the file was
+/// written, so the file only changes when what would be booked does. Lines
+/// are tab separated and sorted, an activity without a class has `-` for it.
+pub async fn write_plan(
+    args: &Args,
+    api: &Api,
+    activities: &[BookableActivity],
+    path: &Path,
+) -> Result<()> {
+    let mut lines = vec![];
+    for activity in activities {
+        let now = clock::now();
+        let classes: Vec<_> = plan(args, api, activity)
+            .await
+            .map_err(|err| {
+                eyre!(
+                    "unable to plan {} for user {}: {}",
+                    &activity.name,
+                    &activity.user_name,
+                    err
+                )
+            })?
+            .into_iter()
+            // Full and closed classes are only previewed to show their status
+            .filter(|it| {
+                bookability::is_candidate(args, activity, it)
+                    || activity.booking_opens_at(args, it) > now
+            })
+            .collect();
+        let key = format!(
+            "{}\t{}\t{}",
+            activity.user_name, activity.name, activity.day
+        );
+        if classes.is_empty() {
+            lines.push(format!("{}\t-\t-\t-", key));
+        }
+        for class in classes {
+            lines.push(format!(
+                "{}\t{}\t{}\t{}",
+                key,
+                parse_date(&class.start_time).format("%Y-%m-%d %H:%M"),
+                class.id,
+                class.name
+            ));
+        }
+    }
+    lines.sort();
+    lines.dedup();
+    let mut text = String::from("user\tactivity\tday\tstart\tclass_id\tclass\n");
+    for line in lines {
+        text += &line;
+        text += "\n";
+    }
+    fs::write(path, text)?;
+    info!("wrote the plan to {}", path.display());
+    Ok(())
+}
+
+/// The class a run now would book, picked from the bookable matches of the
+/// whole span like the run does, and for each day without a bookable match
+/// the match a run would otherwise pick, to show why it isn't bookable yet.
+/// Classes outside `book_only_within_minutes_of_open` are left out.
+async fn plan(args: &Args, api: &Api, activity: &BookableActivity) -> Result<Vec<GroupActivity>> {
+    let now = clock::now();
+    let (from, to) = run_span(args, &now);
+    let url = get_bookings_url(&activity.user_id.to_string(), &activity.id, &from, &to);
+    let mut dto = api.get_group_activities(&url).await?;
+    selection::cap_candidates(args, activity, &mut dto.group_activities);
+    let matches: Vec<_> = dto
+        .group_activities
+        .into_iter()
+        .filter(|it| {
+            selection::is_match(activity, it) && !selection::has_passed(activity, it, &now)
+        })
+        // A run skips the activity quietly outside its window
+        .filter(|it| activity.is_in_booking_window(args, it, &now))
+        .collect();
+    let is_candidate = |it: &GroupActivity| bookability::is_candidate(args, activity, it);
+    let candidates = matches.iter().filter(|it| is_candidate(it));
+    let booked = selection::select_best(args, activity, candidates).map(|it| it.class.clone());
+    let mut dates: Vec<_> = matches
+        .iter()
+        .filter(|it| !is_candidate(it))
+        .map(|it| parse_date(&it.start_time).date_naive())
+        .collect();
+    dates.sort();
+    dates.dedup();
+    let mut classes: Vec<_> = dates
+        .into_iter()
+        .filter_map(|date| {
+            let same_day: Vec<_> = matches
+                .iter()
+                .filter(|it| parse_date(&it.start_time).date_naive() == date)
+                .collect();
+            // A day with a bookable match is the run's to pick from
+            if same_day.iter().any(|it| is_candidate(it)) {
+                return None;
+            }
+            let selection = selection::select_best(args, activity, same_day)?;
+            Some(selection.class.clone())
+        })
+        .chain(booked)
+        .collect();
+    classes.sort_by_key(|it| parse_date(&it.start_time));
+    Ok(classes)
+}
+
+fn row(args: &Args, activity: &BookableActivity, class: &GroupActivity) -> String {
+    let now = clock::now();
+    let opens_at = activity.booking_opens_at(args, class);
+    let status = match (
+        class.booking_id.is_some(),
+        availability(args, activity, class),
+    ) {
+        (true, _) => String::from("already booked"),
+        (false, _) if opens_at > now => format!(
+            "opens {}",
+            opens_at.with_timezone(&CLUB_TZ).format("%Y-%m-%d %H:%M")
+        ),
+        (false, Availability::Bookable) => String::from("bookable"),
+        (false, Availability::Closed) => String::from("closed"),
+        (false, Availability::Full) => String::from("full"),
+        (false, Availability::TooCrowded) => String::from("too crowded"),
+    };
+    format!(
+        "{:<12} {:<24} {:<24} {:<17} {:>5}  {}",
+        activity.user_name,
+        activity.name,
+        class.name,
+        parse_date(&class.start_time).format("%a %Y-%m-%d %H:%M"),
+        class.free_slots,
+        status
+    )
+}
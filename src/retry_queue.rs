@@ -0,0 +1,198 @@
+//! Bookings that fail transiently in watch mode are queued in the state file
+//! and retried in the background until they go through or
+//! `--retry-deadline-minutes` passes. The queue outlives the process, so a
+//! restarted daemon picks up the retries where it left off.
+use crate::api::{is_transient, Api, GroupActivity};
+use crate::cli::Args;
+use crate::confirm::{self, Confirmation};
+use crate::notify::Notifier;
+use crate::outcome::{BookingOutcome, Report};
+use crate::state::State;
+use crate::tz::parse_date;
+use crate::{book_class, hooks, BookableActivity};
+use chrono::{DateTime, Duration, Utc};
+use eyre::Error;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingRetry {
+    /// `BookableActivity::key` of the activity the class was booked for
+    pub key: String,
+    pub class: GroupActivity,
+    pub attempts: u32,
+    pub last_error: String,
+    pub next_retry: DateTime<Utc>,
+    pub deadline: DateTime<Utc>,
+}
+
+/// Queues a failed booking for a retry when the error is transient and the
+/// queue has room. Returns whether it was queued, otherwise the failure is
+/// final.
+pub fn enqueue(
+    args: &Args,
+    path: &Path,
+    activity: &BookableActivity,
+    class: &GroupActivity,
+    err: &Error,
+) -> bool {
+    if !is_transient(err) {
+        return false;
+    }
+    let mut state = State::load(path);
+    if state.retries.len() >= args.retry_queue_size {
+        warn!(
+            "retry queue is full with {} bookings, not retrying {} at {}",
+            state.retries.len(),
+            class.name,
+            class.start_time
+        );
+        return false;
+    }
+    let now = Utc::now();
+    state.retries.push(PendingRetry {
+        key: activity.key(),
+        class: class.clone(),
+        attempts: 1,
+        last_error: err.to_string(),
+        next_retry: now + interval(args),
+        deadline: now + Duration::minutes(args.retry_deadline_minutes),
+    });
+    if let Err(err) = state.save(path) {
+        error!("unable to save state to {}: {}", path.display(), err);
+        return false;
+    }
+    info!(
+        "queued a retry of {} at {} for user {}",
+        class.name, class.start_time, activity.user_name
+    );
+    true
+}
+
+fn interval(args: &Args) -> Duration {
+    Duration::seconds(args.retry_interval_seconds.max(1))
+}
+
+/// Retries the queued bookings that are due, forever. Every booking that
+/// goes through, fails for good or runs out of time is reported.
+pub async fn drain(
+    args: Arc<Args>,
+    api: Arc<Api>,
+    notifier: Arc<Notifier>,
+    state_lock: Arc<Mutex<()>>,
+    activities: Vec<BookableActivity>,
+) {
+    let path = args.state.clone().expect("the retry queue needs --state");
+    loop {
+        tokio::time::sleep(interval(&args).to_std().unwrap_or_default()).await;
+        let guard = state_lock.lock().await;
+        let mut state = State::load(&path);
+        if state.retries.is_empty() {
+            continue;
+        }
+        let now = Utc::now();
+        let mut reports = vec![];
+        let mut pending = vec![];
+        for mut retry in std::mem::take(&mut state.retries) {
+            let Some(activity) = activities.iter().find(|it| it.key() == retry.key) else {
+                warn!(
+                    "dropping the retry of {}, it's no longer configured",
+                    retry.key
+                );
+                continue;
+            };
+            if retry.next_retry > now {
+                pending.push(retry);
+                continue;
+            }
+            if now >= retry.deadline {
+                let reason = format!(
+                    "gave up after {} attempts: {}",
+                    retry.attempts, retry.last_error
+                );
+                warn!(
+                    "{} at {} {}",
+                    retry.class.name, retry.class.start_time, reason
+                );
+                reports.push(activity.report(BookingOutcome::Failed { reason }));
+                continue;
+            }
+            info!(
+                "retrying {} at {} for user {}, attempt {}",
+                retry.class.name,
+                retry.class.start_time,
+                activity.user_name,
+                retry.attempts + 1
+            );
+            // The failed attempt may have gone through after all
+            let outcome = match confirm::is_booked(&api, activity, &retry.class).await {
+                Ok(true) => {
+                    info!(
+                        "{} at {} turned out to be booked already",
+                        retry.class.name, retry.class.start_time
+                    );
+                    Ok(BookingOutcome::Booked {
+                        class: retry.class.name.clone(),
+                        start_time: retry.class.start_time.clone(),
+                        end_time: retry.class.end_time.clone(),
+                        confirmation: Some(Confirmation::Confirmed),
+                    })
+                }
+                Ok(false) => book_class(&args, &api, activity, &retry.class).await,
+                Err(err) => {
+                    warn!(
+                        "unable to check whether {} is booked, retrying later: {}",
+                        retry.class.name, err
+                    );
+                    retry.next_retry = now + interval(&args);
+                    pending.push(retry);
+                    continue;
+                }
+            };
+            match outcome {
+                Ok(outcome) => {
+                    if args.rolling && matches!(outcome, BookingOutcome::Booked { .. }) {
+                        let date = parse_date(&retry.class.start_time).date_naive();
+                        let entry = state.activities.entry(retry.key.clone()).or_default();
+                        entry.secured.push(date);
+                    }
+                    reports.push(activity.report(outcome));
+                }
+                Err(err) if is_transient(&err) => {
+                    warn!("retry of {} failed: {}", retry.class.name, err);
+                    retry.attempts += 1;
+                    retry.last_error = err.to_string();
+                    retry.next_retry = now + interval(&args);
+                    pending.push(retry);
+                }
+                Err(err) => {
+                    error!("{}", err);
+                    reports.push(activity.report(BookingOutcome::Failed {
+                        reason: err.to_string(),
+                    }));
+                }
+            }
+        }
+        state.retries = pending;
+        state.record(&reports, now);
+        if let Err(err) = state.save(&path) {
+            error!("unable to save state to {}: {}", path.display(), err);
+        }
+        drop(guard);
+        report(&args, &api, &notifier, reports).await;
+    }
+}
+
+async fn report(args: &Args, api: &Api, notifier: &Notifier, reports: Vec<Report>) {
+    if reports.is_empty() {
+        return;
+    }
+    for report in &reports {
+        api.record_decision(report);
+    }
+    notifier.notify(&reports).await;
+    hooks::run(args, &reports).await;
+}
@@ -0,0 +1,308 @@
+use crate::api::GroupActivity;
+use crate::cli::{Args, TieBreak};
+use crate::tz::parse_date;
+use crate::{parse_weekday, BookableActivity};
+use chrono::{DateTime, Datelike, Utc};
+use log::{debug, info, warn};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Whether a timeslot is an occurrence of the configured activity, regardless
+/// of whether it can be booked right now
+pub fn is_match(activity: &BookableActivity, candidate: &GroupActivity) -> bool {
+    let is_correct_day = parse_date(&candidate.start_time).weekday()
+        == parse_weekday(&activity.day).expect("invalid week day");
+    is_correct_day
+        && matched_name(activity, candidate).is_some()
+        && meets_min_fields(activity, candidate)
+        && meets_match_fields(activity, candidate)
+        && meets_instructors(activity, candidate)
+}
+
+/// Separators co-teaching instructors might be listed with. Only single
+/// instructors have been seen in `Instructor`, so the format is a guess.
+const INSTRUCTOR_SEPARATORS: &[char] = &[',', '&', '/', '+'];
+
+/// Every instructor of the class, lowercased
+fn instructors(candidate: &GroupActivity) -> Vec<String> {
+    candidate
+        .instructor
+        .split(INSTRUCTOR_SEPARATORS)
+        .flat_map(|it| it.split(" och "))
+        .map(|it| it.trim().to_lowercase())
+        .filter(|it| !it.is_empty())
+        .collect()
+}
+
+/// With `instructors` one of them has to teach the class, and none of
+/// `exclude_instructors` may
+fn meets_instructors(activity: &BookableActivity, candidate: &GroupActivity) -> bool {
+    if activity.instructors.is_none() && activity.exclude_instructors.is_none() {
+        return true;
+    }
+    let teaching = instructors(candidate);
+    let teaches = |names: &Vec<String>| {
+        names
+            .iter()
+            .any(|name| teaching.contains(&name.trim().to_lowercase()))
+    };
+    activity.instructors.as_ref().is_none_or(teaches)
+        && !activity.exclude_instructors.as_ref().is_some_and(teaches)
+}
+
+/// Every field named in `min_fields` has to be a number of at least the given
+/// value. A field the API doesn't send never meets it.
+fn meets_min_fields(activity: &BookableActivity, candidate: &GroupActivity) -> bool {
+    let Some(min_fields) = &activity.min_fields else {
+        return true;
+    };
+    let fields = serde_json::to_value(candidate).expect("timeslots serialize to json");
+    min_fields.iter().all(|(field, min)| {
+        let value = fields.get(field).and_then(|it| it.as_f64());
+        if value.is_none() {
+            debug!(
+                "{} at {} has no numeric {}",
+                candidate.name, candidate.start_time, field
+            );
+        }
+        value.is_some_and(|it| it >= *min)
+    })
+}
+
+/// One value or any of several a field of `match_fields` has to equal
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum FieldValues {
+    Any(Vec<serde_json::Value>),
+    One(serde_json::Value),
+}
+
+impl FieldValues {
+    fn contains(&self, value: &serde_json::Value) -> bool {
+        match self {
+            FieldValues::One(it) => it == value,
+            FieldValues::Any(values) => values.contains(value),
+        }
+    }
+}
+
+/// Every field named in `match_fields` has to equal one of its values. No
+/// category field has been seen in the timeslot response, this is how to
+/// filter on one, or any other field, once the API sends it.
+fn meets_match_fields(activity: &BookableActivity, candidate: &GroupActivity) -> bool {
+    let Some(match_fields) = &activity.match_fields else {
+        return true;
+    };
+    let fields = serde_json::to_value(candidate).expect("timeslots serialize to json");
+    match_fields.iter().all(|(field, values)| {
+        let value = fields.get(field);
+        if value.is_none() {
+            debug!(
+                "{} at {} has no {}",
+                candidate.name, candidate.start_time, field
+            );
+        }
+        value.is_some_and(|it| values.contains(it))
+    })
+}
+
+/// Which field of the class the configured name was found in
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchedIn {
+    Name,
+    /// Only with `match_description`, when the class name doesn't match
+    Description,
+}
+
+/// The configured name, or failing that the first alias or translation, found
+/// in the class name, or with `match_description` in its description
+pub fn matched_name<'a>(
+    activity: &'a BookableActivity,
+    candidate: &GroupActivity,
+) -> Option<(&'a str, MatchedIn)> {
+    let find_in = |text: &str| {
+        let text = text.to_lowercase();
+        std::iter::once(&activity.name)
+            .chain(activity.aliases.iter().flatten())
+            .chain(&activity.translations)
+            .find(|name| text.contains(&name.to_lowercase()))
+            .map(|it| it.as_str())
+    };
+    if let Some(name) = find_in(&candidate.name) {
+        return Some((name, MatchedIn::Name));
+    }
+    let description = candidate
+        .description
+        .as_deref()
+        .filter(|_| activity.match_description.unwrap_or(false))?;
+    find_in(description).map(|name| (name, MatchedIn::Description))
+}
+
+/// Bounds matching when a response has far more classes than any week at the
+/// club does, e.g. from a span gone wrong. Past the cap the classes that
+/// match the activity are kept first, then the soonest.
+pub fn cap_candidates(args: &Args, activity: &BookableActivity, classes: &mut Vec<GroupActivity>) {
+    let cap = activity.max_candidates.unwrap_or(args.max_candidates);
+    if classes.len() <= cap {
+        return;
+    }
+    warn!(
+        "the response for {} has {} classes, only considering {} of them",
+        activity.name,
+        classes.len(),
+        cap
+    );
+    classes.sort_by_cached_key(|it| (!is_match(activity, it), parse_date(&it.start_time)));
+    classes.truncate(cap);
+}
+
+/// Warns when the activity id returned classes but none of them has the
+/// configured name on any day, which points at a wrong `id` or `name` rather
+/// than a week without the class
+pub fn warn_if_misnamed(activity: &BookableActivity, classes: &[GroupActivity]) {
+    if classes.is_empty()
+        || classes
+            .iter()
+            .any(|it| matched_name(activity, it).is_some())
+    {
+        return;
+    }
+    let mut names: Vec<_> = classes.iter().map(|it| it.name.as_str()).collect();
+    names.sort();
+    names.dedup();
+    warn!(
+        "activity id {} returned classes but none named '{}', is the id or name wrong? It returned {}",
+        activity.id,
+        activity.name,
+        names.join(", ")
+    );
+}
+
+/// Which end of a class decides that it's in the past and can't be booked
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExcludeAfter {
+    #[default]
+    Start,
+    End,
+}
+
+pub fn has_passed(
+    activity: &BookableActivity,
+    candidate: &GroupActivity,
+    now: &DateTime<Utc>,
+) -> bool {
+    let time = match activity.exclude_after.unwrap_or_default() {
+        ExcludeAfter::Start => &candidate.start_time,
+        ExcludeAfter::End => &candidate.end_time,
+    };
+    parse_date(time) <= *now
+}
+
+/// Scores a candidate for the given activity, higher is better.
+///
+/// Every factor is a soft preference, candidates are never excluded here.
+fn score(activity: &BookableActivity, candidate: &GroupActivity) -> f64 {
+    let free_slots_weight = activity.free_slots_weight.unwrap_or(0.0);
+    free_slots_weight * candidate.free_slots as f64
+}
+
+/// Why a candidate was picked, part of the structured decision log
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    OnlyMatch,
+    HighestScore,
+    FirstOfTied,
+    /// `--tie-break random` picked one of several candidates scoring the same
+    RandomOfTied,
+    /// Watch mode picked the class when scheduling it
+    Scheduled,
+    /// A full class watched by `--monitor-waitlist` got a free spot
+    Promoted,
+}
+
+pub struct Selection<'a> {
+    pub class: &'a GroupActivity,
+    pub reason: Reason,
+}
+
+/// Picks the best scoring candidate. Ties keep the order of the API response,
+/// which makes the previous first-match behaviour the default. With
+/// `--tie-break random` any candidate within `--tie-tolerance` of the best
+/// score may be picked instead.
+pub fn select_best<'a>(
+    args: &Args,
+    activity: &BookableActivity,
+    candidates: impl IntoIterator<Item = &'a GroupActivity>,
+) -> Option<Selection<'a>> {
+    let scored: Vec<_> = candidates
+        .into_iter()
+        .map(|it| (score(activity, it), it))
+        .collect();
+    let best_score = scored
+        .iter()
+        .map(|(score, _)| *score)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let tolerance = match args.tie_break {
+        TieBreak::First => 0.0,
+        TieBreak::Random => args.tie_tolerance.max(0.0),
+    };
+    let top: Vec<_> = scored
+        .iter()
+        .filter(|(score, _)| *score >= best_score - tolerance)
+        .map(|(_, it)| *it)
+        .collect();
+    let reason = match (scored.len(), top.len()) {
+        (1, _) => Reason::OnlyMatch,
+        (_, 1) => Reason::HighestScore,
+        _ if args.tie_break == TieBreak::Random => Reason::RandomOfTied,
+        _ => Reason::FirstOfTied,
+    };
+    let class = match reason {
+        Reason::RandomOfTied => {
+            let mut rng = match args.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            };
+            let class = *top.choose(&mut rng)?;
+            info!(
+                "picked {} at {} at random among {} tied classes of {}",
+                class.name,
+                class.start_time,
+                top.len(),
+                &activity.name
+            );
+            class
+        }
+        _ => *top.first()?,
+    };
+    Some(Selection { class, reason })
+}
+
+/// Names in one language mapped to the club's names in the other, e.g.
+/// `{"Cycling": ["Cykel"]}`. Lookups are case insensitive and work both ways.
+pub type Translations = HashMap<String, Vec<String>>;
+
+/// Every translation of the activity's name and aliases
+pub fn translate(translations: &Translations, activity: &BookableActivity) -> Vec<String> {
+    let names: Vec<_> = std::iter::once(&activity.name)
+        .chain(activity.aliases.iter().flatten())
+        .map(|it| it.to_lowercase())
+        .collect();
+    let mut translated = vec![];
+    for (name, others) in translations {
+        if names.contains(&name.to_lowercase()) {
+            translated.extend(others.iter().cloned());
+        }
+        if others.iter().any(|it| names.contains(&it.to_lowercase())) {
+            translated.push(name.clone());
+        }
+    }
+    translated.retain(|it| !names.contains(&it.to_lowercase()));
+    translated
+}
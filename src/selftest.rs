@@ -0,0 +1,60 @@
+//! `--selftest` checks the setup end to end without booking anything: the
+//! activities config, that the API answers and parses, and that every
+//! notifier gets a test message through. `--check-notifiers` runs only the
+//! last check. Each check is logged, and the run fails if any of them did.
+use crate::api::{get_bookings_url, run_span, Api};
+use crate::cli::Args;
+use crate::notify::Notifier;
+use crate::{clock, BookableActivity};
+use eyre::{eyre, Result};
+use log::{error, info};
+
+pub async fn run(
+    args: &Args,
+    api: &Api,
+    activities: &[BookableActivity],
+    notifier: &Notifier,
+) -> Result<()> {
+    info!(
+        "config has {} activities, {} of them enabled",
+        activities.len(),
+        activities
+            .iter()
+            .filter(|it| !it.disabled.unwrap_or(false))
+            .count()
+    );
+    let mut ok = true;
+    match api.clock_skew().await {
+        Ok(_) => info!("the api answers"),
+        Err(err) => {
+            error!("the api doesn't answer: {}", err);
+            ok = false;
+        }
+    }
+    if let Some(activity) = activities.iter().find(|it| !it.disabled.unwrap_or(false)) {
+        let (from, to) = run_span(args, &clock::now());
+        let url = get_bookings_url(&activity.user_id.to_string(), &activity.id, &from, &to);
+        match api.get_group_activities(&url).await {
+            Ok(dto) => info!(
+                "fetched {} classes for activity {} of user {}",
+                dto.group_activities.len(),
+                activity.name,
+                activity.user_name
+            ),
+            Err(err) => {
+                error!(
+                    "unable to fetch the classes of activity {} for user {}: {}",
+                    activity.name, activity.user_name, err
+                );
+                ok = false;
+            }
+        }
+    }
+    ok &= notifier.check().await;
+    if ok {
+        info!("every check passed");
+        Ok(())
+    } else {
+        Err(eyre!("some checks failed"))
+    }
+}
@@ -0,0 +1,156 @@
+//! Support bundles: `--record <dir>` writes every request, response and
+//! decision of a run to timestamped files with user ids and names redacted,
+//! `--replay <dir>` runs against such a recording offline.
+use crate::logging;
+use crate::outcome::Report;
+use chrono::Utc;
+use eyre::{Error, Result};
+use log::warn;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const REDACTED: &str = "REDACTED";
+
+pub struct Recorder {
+    dir: PathBuf,
+    run: String,
+    seq: AtomicUsize,
+}
+
+impl Recorder {
+    pub fn new(dir: &Path) -> Result<Recorder> {
+        fs::create_dir_all(dir)?;
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+            run: Utc::now().format("%Y%m%dT%H%M%SZ").to_string(),
+            seq: AtomicUsize::new(0),
+        })
+    }
+
+    /// Recording is best effort, a bundle missing a file beats a failed booking
+    fn write(&self, kind: &str, value: &impl Serialize) {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let path = self
+            .dir
+            .join(format!("{}-{:03}-{}.json", self.run, seq, kind));
+        let result = serde_json::to_string_pretty(value)
+            .map_err(Error::from)
+            .and_then(|it| fs::write(&path, it).map_err(Error::from));
+        if let Err(err) = result {
+            warn!("unable to record {}: {}", path.display(), err);
+        }
+    }
+
+    pub fn timeslot(&self, url: &str, body: &str) {
+        self.write(
+            "timeslot",
+            &json!({ "url": redact_url(url), "body": body_value(body) }),
+        );
+    }
+
+    pub fn booking(&self, form: &Value, status: u16, body: &str) {
+        let mut form = form.clone();
+        form["UserId"] = json!(REDACTED);
+        self.write(
+            "booking",
+            &json!({ "form": form, "status": status, "body": body_value(body) }),
+        );
+    }
+
+    /// Every string of the decision is redacted, a failure reason or the
+    /// notes can name the user as well
+    pub fn decision(&self, report: &Report) {
+        let user_id = report.key.split('/').next().unwrap_or_default();
+        let mut value = json!(report);
+        value["key"] = json!(REDACTED);
+        redact_strings(&mut value, user_id, &report.user_name);
+        self.write("decision", &value);
+    }
+}
+
+/// Masks the user id and name in every string in the value, and whatever
+/// `--redact` masks in the log
+fn redact_strings(value: &mut Value, user_id: &str, user_name: &str) {
+    match value {
+        Value::String(text) => {
+            let mut redacted = logging::redact(text);
+            if !user_name.is_empty() {
+                redacted = redacted.replace(user_name, REDACTED);
+            }
+            if !user_id.is_empty() {
+                redacted = logging::replace_standalone(&redacted, user_id, REDACTED);
+            }
+            *text = redacted;
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|it| redact_strings(it, user_id, user_name)),
+        Value::Object(values) => values
+            .values_mut()
+            .for_each(|it| redact_strings(it, user_id, user_name)),
+        _ => (),
+    }
+}
+
+/// Keeps the body as json when it is json so the bundle stays readable
+fn body_value(body: &str) -> Value {
+    serde_json::from_str(body).unwrap_or_else(|_| json!(body))
+}
+
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    let query = url.split_once('?')?.1;
+    query
+        .split('&')
+        .find_map(|it| it.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn redact_url(url: &str) -> String {
+    match query_param(url, "userId") {
+        Some(user_id) => url.replace(&format!("userId={user_id}"), &format!("userId={REDACTED}")),
+        None => url.to_string(),
+    }
+}
+
+/// Timeslot responses of a recorded run, looked up by activity id
+pub struct Replay {
+    timeslots: Vec<(String, Value)>,
+}
+
+impl Replay {
+    pub fn load(dir: &Path) -> Result<Replay> {
+        let mut paths: Vec<_> = fs::read_dir(dir)?
+            .filter_map(|it| it.ok().map(|it| it.path()))
+            .filter(|it| it.to_string_lossy().ends_with("-timeslot.json"))
+            .collect();
+        paths.sort();
+        let mut timeslots = vec![];
+        for path in paths {
+            let recorded: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+            let url = recorded["url"].as_str().unwrap_or_default();
+            if let Some(activity_id) = query_param(url, "activities") {
+                timeslots.push((activity_id.to_string(), recorded["body"].clone()));
+            }
+        }
+        Ok(Replay { timeslots })
+    }
+
+    /// The latest recorded response for the activity requested by `url`
+    pub fn timeslot(&self, url: &str) -> Result<String> {
+        let activity_id = query_param(url, "activities").unwrap_or_default();
+        let (_, body) = self
+            .timeslots
+            .iter()
+            .rev()
+            .find(|(id, _)| id == activity_id)
+            .ok_or_else(|| {
+                Error::msg(format!("no recorded timeslots for activity {activity_id}"))
+            })?;
+        Ok(match body {
+            Value::String(text) => text.clone(),
+            body => body.to_string(),
+        })
+    }
+}
@@ -0,0 +1,230 @@
+use crate::outcome::{summary_text, BookingOutcome, Decision, Report};
+use crate::retry_queue::PendingRetry;
+use crate::selection::Reason;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use eyre::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// What is remembered between runs, keyed by `BookableActivity::key`
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    pub last_run: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub activities: BTreeMap<String, ActivityState>,
+    /// Watch mode bookings waiting to be tried again
+    #[serde(default)]
+    pub retries: Vec<PendingRetry>,
+    /// Every activity's outcome in the latest run, for `--notify-policy on-change`
+    #[serde(default)]
+    pub last_outcomes: BTreeMap<String, String>,
+    /// The latest summary sent, for `--notify-dedup-minutes`
+    #[serde(default)]
+    pub last_notification: Option<SentNotification>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SentNotification {
+    /// Hex SHA-256 of the summary text, stable across builds and only
+    /// compared within the dedup window
+    pub hash: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ActivityState {
+    /// Runs in a row that found no class to book
+    #[serde(default)]
+    pub not_found_streak: u32,
+    #[serde(default)]
+    pub stats: Stats,
+    /// Classes that were booked but not attended, marked by hand since the
+    /// API doesn't report attendance
+    #[serde(default)]
+    pub no_shows: Vec<DateTime<Utc>>,
+    /// Upcoming dates `--rolling` has booked, so a restart doesn't book them again
+    #[serde(default)]
+    pub secured: Vec<NaiveDate>,
+    /// Every class that was booked, for `--weekly-report`
+    #[serde(default)]
+    pub history: Vec<BookedClass>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BookedClass {
+    pub user_name: String,
+    pub class: String,
+    /// Club local time, as the API sends it
+    pub start_time: String,
+}
+
+/// Outcomes of every attempt of an activity, for spotting config entries that
+/// rarely resolve cleanly
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Stats {
+    pub attempts: u32,
+    /// Booked, or would have been in a dry run
+    pub booked: u32,
+    /// Nothing was bookable because every match was full or too crowded
+    pub full: u32,
+    pub not_found: u32,
+    /// Several classes were tied for the best score
+    pub ambiguous: u32,
+    /// Summed over all attempts, for the average
+    pub candidates: u64,
+}
+
+impl Stats {
+    fn record(&mut self, outcome: &BookingOutcome, decision: &Decision) {
+        self.attempts += 1;
+        self.candidates += decision.candidates as u64;
+        match outcome {
+            BookingOutcome::Booked { .. } | BookingOutcome::WouldBook { .. } => self.booked += 1,
+            BookingOutcome::NotFound => self.not_found += 1,
+            _ => (),
+        }
+        if decision.candidates == 0 && decision.full > 0 {
+            self.full += 1;
+        }
+        if matches!(
+            decision.reason,
+            Some(Reason::FirstOfTied | Reason::RandomOfTied)
+        ) {
+            self.ambiguous += 1;
+        }
+    }
+
+    fn percent(&self, count: u32) -> f64 {
+        100.0 * count as f64 / self.attempts.max(1) as f64
+    }
+}
+
+impl State {
+    /// A missing or unreadable state file starts from scratch rather than
+    /// stopping the booking
+    pub fn load(path: &Path) -> State {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return State::default(),
+        };
+        match serde_json::from_str(&contents) {
+            Ok(state) => state,
+            Err(err) => {
+                warn!("ignoring invalid state file {}: {}", path.display(), err);
+                State::default()
+            }
+        }
+    }
+
+    /// Writes to a temporary file first so a crash never leaves half a state file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn record(&mut self, reports: &[Report], now: DateTime<Utc>) {
+        self.last_run = Some(now);
+        for report in reports {
+            let activity = self.activities.entry(report.key.clone()).or_default();
+            match report.outcome {
+                BookingOutcome::NotFound => activity.not_found_streak += 1,
+                BookingOutcome::Failed { .. } | BookingOutcome::Blocked { .. } => (),
+                _ => activity.not_found_streak = 0,
+            }
+            if let Some(decision) = &report.decision {
+                activity.stats.record(&report.outcome, decision);
+            }
+            if let BookingOutcome::Booked {
+                class, start_time, ..
+            } = &report.outcome
+            {
+                let known = activity
+                    .history
+                    .iter()
+                    .any(|it| it.class == *class && it.start_time == *start_time);
+                if !known {
+                    activity.history.push(BookedClass {
+                        user_name: report.user_name.clone(),
+                        class: class.clone(),
+                        start_time: start_time.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Whether the reports' outcomes differ from the latest recorded run's
+    pub fn outcomes_changed(&self, reports: &[Report]) -> bool {
+        outcomes(reports) != self.last_outcomes
+    }
+
+    /// Replaces the latest run's outcomes, only for regular runs since watch
+    /// mode reports one booking at a time
+    pub fn record_outcomes(&mut self, reports: &[Report]) {
+        self.last_outcomes = outcomes(reports);
+    }
+
+    /// Records the run's summary as sent, unless the same summary was sent
+    /// within the window. Returns whether it should be sent.
+    pub fn record_notification(
+        &mut self,
+        reports: &[Report],
+        now: DateTime<Utc>,
+        window: Duration,
+    ) -> bool {
+        let hash: String = Sha256::digest(summary_text(reports).as_bytes())
+            .iter()
+            .map(|it| format!("{:02x}", it))
+            .collect();
+        let is_duplicate = self
+            .last_notification
+            .as_ref()
+            .is_some_and(|it| it.hash == hash && now - it.sent_at < window);
+        if is_duplicate {
+            return false;
+        }
+        self.last_notification = Some(SentNotification { hash, sent_at: now });
+        true
+    }
+
+    pub fn no_shows_since(&self, key: &str, since: &DateTime<Utc>) -> usize {
+        self.activities
+            .get(key)
+            .map_or(0, |it| it.no_shows.iter().filter(|it| *it >= since).count())
+    }
+
+    /// A table of every activity's stats, meant for `--stats`
+    pub fn stats_text(&self) -> String {
+        let mut text = format!(
+            "{:<40} {:>8} {:>7} {:>7} {:>9} {:>9} {:>10}",
+            "activity", "attempts", "booked", "full", "not found", "ambiguous", "candidates"
+        );
+        for (key, activity) in &self.activities {
+            let stats = &activity.stats;
+            text += &format!(
+                "\n{:<40} {:>8} {:>6.0}% {:>6.0}% {:>8.0}% {:>8.0}% {:>10.1}",
+                key,
+                stats.attempts,
+                stats.percent(stats.booked),
+                stats.percent(stats.full),
+                stats.percent(stats.not_found),
+                stats.percent(stats.ambiguous),
+                stats.candidates as f64 / stats.attempts.max(1) as f64
+            );
+        }
+        text
+    }
+}
+
+fn outcomes(reports: &[Report]) -> BTreeMap<String, String> {
+    reports
+        .iter()
+        .map(|it| (it.key.clone(), it.outcome.to_string()))
+        .collect()
+}
@@ -0,0 +1,34 @@
+//! With the `systemd` feature watch mode runs as a `Type=notify` service: it
+//! signals `READY=1` once it's set up and, when the unit sets `WatchdogSec`,
+//! pings the watchdog at half that interval. The pings come from a task on
+//! the same runtime as the bookings, so a daemon whose runtime hangs stops
+//! pinging and gets restarted. Outside systemd both do nothing.
+#[cfg(feature = "systemd")]
+use log::{debug, info, warn};
+#[cfg(feature = "systemd")]
+use sd_notify::NotifyState;
+
+#[cfg(feature = "systemd")]
+pub fn ready() {
+    match sd_notify::notify(false, &[NotifyState::Ready]) {
+        Ok(()) => debug!("notified systemd that the daemon is ready"),
+        Err(err) => warn!("unable to notify systemd: {}", err),
+    }
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let interval = std::time::Duration::from_micros(usec / 2);
+    info!("pinging the systemd watchdog every {:?}", interval);
+    tokio::task::spawn(async move {
+        loop {
+            if let Err(err) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                warn!("unable to ping the systemd watchdog: {}", err);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn ready() {}
@@ -0,0 +1,80 @@
+//! The timeslot API returns `StartTime`/`EndTime` as local wall clock time
+//! without an offset (e.g. `2023-04-09T10:00:00`), and no response field or
+//! header names the club's timezone. A timestamp that does carry an offset is
+//! trusted as is, anything else is read as `Europe/Stockholm` where all the
+//! clubs are.
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use log::{info, warn};
+
+pub const CLUB_TZ: Tz = chrono_tz::Europe::Stockholm;
+
+/// chrono-tz compiles the tz database into the binary, so the zone can't go
+/// missing at runtime the way a system zoneinfo lookup could in a stripped
+/// container. There is no fixed offset fallback for that reason.
+///
+/// The flip side is that DST rules are frozen at build time, so a binary that
+/// runs for years misses rule changes. Only the release year is known, e.g.
+/// `2024a`, so the age is counted in years.
+pub fn log_mechanism(max_age_years: i32) {
+    info!(
+        "reading club times as {} from tz database {} built into the binary, currently {}",
+        CLUB_TZ.name(),
+        chrono_tz::IANA_TZDB_VERSION,
+        Utc::now().with_timezone(&CLUB_TZ).format("UTC%:z")
+    );
+    let Some(year) = chrono_tz::IANA_TZDB_VERSION
+        .get(..4)
+        .and_then(|it| it.parse::<i32>().ok())
+    else {
+        return;
+    };
+    let age = Utc::now().year() - year;
+    if age > max_age_years {
+        warn!(
+            "tz database {} is {} years old, rebuild with an updated chrono-tz to pick up any DST rule changes",
+            chrono_tz::IANA_TZDB_VERSION,
+            age
+        );
+    }
+}
+
+/// Formats the club's local date at the given instant the way the API expects it
+pub fn get_nw_date(time: &DateTime<Utc>) -> String {
+    time.with_timezone(&CLUB_TZ)
+        .date_naive()
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// Parses a timestamp from the API into the club's local time
+pub fn parse_date(date_str: &str) -> DateTime<FixedOffset> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(date_str) {
+        return datetime;
+    }
+    let naive = NaiveDateTime::parse_from_str(date_str, "%Y-%m-%dT%H:%M:%S")
+        .expect("invalid date from the api");
+    from_club_local(&naive)
+}
+
+pub fn from_club_local(naive: &NaiveDateTime) -> DateTime<FixedOffset> {
+    // Times skipped by a DST transition don't exist locally, the clock
+    // reads an hour later at that instant
+    let local = CLUB_TZ
+        .from_local_datetime(naive)
+        .earliest()
+        .or_else(|| {
+            CLUB_TZ
+                .from_local_datetime(&(*naive + Duration::hours(1)))
+                .earliest()
+        })
+        .expect("time out of bounds");
+    local.with_timezone(&local.offset().fix())
+}
+
+/// Booking of a class opens at the same local wall clock time the given number
+/// of days before it starts, a DST change in between doesn't shift it
+pub fn booking_opens_at(start: &DateTime<FixedOffset>, days_before: i64) -> DateTime<Utc> {
+    let opens = start.naive_local() - Duration::days(days_before);
+    from_club_local(&opens).with_timezone(&Utc)
+}
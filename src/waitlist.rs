@@ -0,0 +1,183 @@
+//! `--monitor-waitlist` keeps polling the activities marked `monitor_waitlist`
+//! and books a class the moment a spot in it frees up. The API doesn't tell
+//! whether the user is queued for a class, so every matching class that is
+//! full when monitoring starts is watched until it has free slots.
+use crate::api::{self, get_bookings_url, Api, GroupActivity};
+use crate::bookability::{availability, has_free_spot, Availability};
+use crate::cli::Args;
+use crate::notify::Notifier;
+use crate::outcome::{BookingOutcome, Decision, Report};
+use crate::selection::Reason;
+use crate::{book_class, selection, BookableActivity};
+use crate::{hooks, logging};
+use chrono::{Duration, Utc};
+use eyre::Result;
+use log::{error, info, warn};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+pub async fn run(
+    args: Arc<Args>,
+    api: Arc<Api>,
+    activities: Vec<BookableActivity>,
+    notifier: Notifier,
+) -> Result<()> {
+    let mut monitored: Vec<_> = activities
+        .into_iter()
+        .filter(|it| it.monitor_waitlist.unwrap_or(false))
+        .map(|it| (it, None))
+        .collect();
+    if monitored.is_empty() {
+        warn!("no activity has monitor_waitlist set, nothing to monitor");
+        return Ok(());
+    }
+    let interval = std::time::Duration::from_secs(args.waitlist_poll_seconds.max(1));
+    info!("monitoring {} activities for free spots", monitored.len());
+
+    while !monitored.is_empty() {
+        let mut finished = vec![];
+        for (index, (activity, full)) in monitored.iter_mut().enumerate() {
+            match poll(&args, &api, activity, full).await {
+                Ok(Poll::Waiting) => (),
+                Ok(Poll::Done) => finished.push(index),
+                Ok(Poll::Promoted(class)) => {
+                    let (report, transient) = promote(&args, &api, activity, &class).await;
+                    // Only a booking that never reached the API is tried again while the
+                    // spot is free, and only its final outcome is sent on
+                    if transient {
+                        warn!("polling {} again to retry the booking", &activity.name);
+                    } else {
+                        let reports = [report];
+                        notifier.notify(&reports).await;
+                        hooks::run(&args, &reports).await;
+                        finished.push(index);
+                    }
+                }
+                Err(err) => warn!("{}, polling {} again", err, &activity.name),
+            }
+        }
+        for index in finished.into_iter().rev() {
+            monitored.remove(index);
+        }
+        if !monitored.is_empty() {
+            tokio::time::sleep(interval).await;
+        }
+    }
+    info!("no waitlisted classes left to monitor");
+    Ok(())
+}
+
+enum Poll {
+    Waiting,
+    /// Every watched class has passed
+    Done,
+    /// A watched class got a free spot
+    Promoted(Box<GroupActivity>),
+}
+
+/// Fetches the activity's classes and picks the best watched class that has
+/// free slots again. `full` holds the ids of the classes being watched, the
+/// ones that were full on the first poll, and is `None` before it.
+async fn poll(
+    args: &Args,
+    api: &Api,
+    activity: &BookableActivity,
+    full: &mut Option<HashSet<i64>>,
+) -> Result<Poll> {
+    let now = Utc::now();
+    let url = get_bookings_url(
+        &activity.user_id.to_string(),
+        &activity.id,
+        &now,
+        &(now + Duration::weeks(1)),
+    );
+    let mut dto = api.get_group_activities(&url).await?;
+    selection::cap_candidates(args, activity, &mut dto.group_activities);
+    let upcoming: Vec<_> = dto
+        .group_activities
+        .into_iter()
+        .filter(|it| {
+            selection::is_match(activity, it)
+                && it.booking_id.is_none()
+                && !selection::has_passed(activity, it, &now)
+        })
+        .collect();
+    let full = full.get_or_insert_with(|| {
+        let first: Vec<_> = upcoming
+            .iter()
+            .filter(|it| availability(args, activity, it) == Availability::Full)
+            .collect();
+        for class in &first {
+            info!(
+                "watching {} at {} for user {} for a free spot",
+                class.name, class.start_time, &activity.user_name
+            );
+        }
+        first.iter().map(|it| it.id).collect()
+    });
+    let watched: Vec<_> = upcoming.iter().filter(|it| full.contains(&it.id)).collect();
+    let (freed, still_full): (Vec<_>, Vec<_>) = watched.into_iter().partition(|it| {
+        availability(args, activity, it) == Availability::Bookable && has_free_spot(args, it)
+    });
+    if let Some(selection) = selection::select_best(args, activity, freed) {
+        return Ok(Poll::Promoted(Box::new(selection.class.clone())));
+    }
+    // Watched classes that passed or stopped taking bookings are dropped
+    full.retain(|id| {
+        still_full.iter().any(|it| {
+            it.id == *id
+                && matches!(
+                    availability(args, activity, it),
+                    Availability::Full | Availability::TooCrowded
+                )
+        })
+    });
+    if full.is_empty() {
+        info!(
+            "{} for user {} has no full classes left to watch",
+            &activity.name, &activity.user_name
+        );
+        return Ok(Poll::Done);
+    }
+    Ok(Poll::Waiting)
+}
+
+/// Books the freed spot, and tells whether a failure is worth another try.
+async fn promote(
+    args: &Args,
+    api: &Api,
+    activity: &BookableActivity,
+    class: &GroupActivity,
+) -> (Report, bool) {
+    info!(
+        "a spot opened in {} at {} with {} free slots, booking it for user {}",
+        class.name, class.start_time, class.free_slots, &activity.user_name
+    );
+    let mut decision = Decision::start(activity.key());
+    decision.class_id = Some(class.id);
+    decision.candidates = 1;
+    decision.reason = Some(Reason::Promoted);
+    let mut transient = false;
+    let outcome = match book_class(args, api, activity, class).await {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            error!("{}", err.to_string());
+            transient = api::is_transient(&err);
+            BookingOutcome::Failed {
+                reason: err.to_string(),
+            }
+        }
+    };
+    decision.booking_ms = Some(decision.elapsed_ms());
+    decision.finish(&outcome);
+    logging::log_decision(args, &decision);
+    if let BookingOutcome::Booked { .. } = outcome {
+        info!(
+            "promoted from the waitlist of {} for user {}",
+            class.name, &activity.user_name
+        );
+    }
+    let report = activity.report(outcome);
+    api.record_decision(&report);
+    (report, transient)
+}
@@ -0,0 +1,424 @@
+//! `--watch` keeps the booker running as a daemon. It fetches every matching
+//! class within the horizon, works out when each one opens for booking and
+//! fires the booking at exactly that moment. After a class fires its activity
+//! is rescheduled, which picks up occurrences that have entered the horizon.
+//! `--rolling` also remembers the booked occurrences in the state file, so a
+//! restarted daemon carries on where it left off.
+use crate::api::{get_bookings_url, Api, GroupActivity};
+use crate::bookability;
+use crate::cli::Args;
+use crate::notify::Notifier;
+use crate::outcome::{BookingOutcome, Decision};
+use crate::selection::Reason;
+use crate::state::State;
+use crate::tz::{parse_date, CLUB_TZ};
+use crate::{book_class, selection, BookableActivity};
+use crate::{hooks, logging, retry_queue, systemd};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use eyre::Result;
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A single class of an activity and the moment it opens for booking
+struct Scheduled {
+    activity: BookableActivity,
+    class: GroupActivity,
+    opens_at: DateTime<Utc>,
+}
+
+/// Bookings per user, group and day, for `--group-daily-cap`
+type GroupCounts = Arc<std::sync::Mutex<HashMap<(u32, String, NaiveDate), usize>>>;
+
+/// A place in the count of a group, held while its booking is in flight and
+/// given back unless the booking succeeds or is queued for a retry.
+struct GroupPlace {
+    counts: GroupCounts,
+    key: (u32, String, NaiveDate),
+}
+
+impl GroupPlace {
+    fn release(self) {
+        if let Some(count) = self.counts.lock().unwrap().get_mut(&self.key) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+pub async fn run(
+    args: Arc<Args>,
+    api: Arc<Api>,
+    activities: Vec<BookableActivity>,
+    notifier: Notifier,
+) -> Result<()> {
+    let notifier = Arc::new(notifier);
+    let skew = measure_clock_skew(&args, &api).await;
+    // The clock the classes open by, the API's when compensating for skew
+    let server_now = || Utc::now() + skew;
+    let refresh = Duration::minutes(args.watch_refresh_minutes.max(1));
+    // Days that have been fired at, so a failed booking isn't retried forever
+    let mut fired = HashSet::new();
+    // Bookings made or in flight per user, group and day
+    let group_counts = GroupCounts::default();
+    // Fired bookings and retries update the state one at a time
+    let state_lock = args.state.as_ref().map(|_| Arc::new(Mutex::new(())));
+    if let Some(path) = &args.state {
+        let state = State::load(path);
+        if args.rolling {
+            for (key, activity) in &state.activities {
+                fired.extend(activity.secured.iter().map(|date| (key.clone(), *date)));
+            }
+            info!(
+                "rolling from {}, {} occurrences already secured",
+                path.display(),
+                fired.len()
+            );
+        }
+        for retry in &state.retries {
+            let date = parse_date(&retry.class.start_time).date_naive();
+            fired.insert((retry.key.clone(), date));
+        }
+        if !state.retries.is_empty() {
+            info!("resuming {} queued retries", state.retries.len());
+        }
+    }
+    if let Some(lock) = &state_lock {
+        tokio::task::spawn(retry_queue::drain(
+            args.clone(),
+            api.clone(),
+            notifier.clone(),
+            lock.clone(),
+            activities.clone(),
+        ));
+    }
+    let mut schedule = vec![];
+    let mut next_refresh = server_now();
+    systemd::ready();
+
+    loop {
+        if server_now() >= next_refresh {
+            let today = Utc::now().with_timezone(&CLUB_TZ).date_naive();
+            fired.retain(|(_, date)| *date >= today);
+            group_counts
+                .lock()
+                .unwrap()
+                .retain(|(_, _, date), _| *date >= today);
+            schedule.clear();
+            for activity in &activities {
+                schedule.extend(schedule_activity(&args, &api, activity, &fired).await);
+            }
+            log_schedule(&schedule);
+            next_refresh = server_now() + refresh;
+        }
+
+        let now = server_now();
+        let (due, pending): (Vec<_>, Vec<_>) =
+            schedule.into_iter().partition(|it| it.opens_at <= now);
+        schedule = pending;
+        let mut rescheduled = vec![];
+        for scheduled in due {
+            let date = parse_date(&scheduled.class.start_time).date_naive();
+            fired.insert((scheduled.activity.key(), date));
+            if !rescheduled.contains(&scheduled.activity.key()) {
+                rescheduled.push(scheduled.activity.key());
+            }
+            let mut group_place = None;
+            if let Some(group) = &scheduled.activity.group {
+                let key = (scheduled.activity.user_id, group.clone(), date);
+                let mut counts = group_counts.lock().unwrap();
+                let count = counts.entry(key.clone()).or_default();
+                if *count >= args.group_daily_cap.max(1) {
+                    info!(
+                        "skipping {} at {} for user {}, group {} already has {} bookings on {}",
+                        scheduled.class.name,
+                        scheduled.class.start_time,
+                        scheduled.activity.user_name,
+                        group,
+                        count,
+                        date
+                    );
+                    continue;
+                }
+                *count += 1;
+                group_place = Some(GroupPlace {
+                    counts: group_counts.clone(),
+                    key,
+                });
+            }
+            fire(
+                args.clone(),
+                api.clone(),
+                notifier.clone(),
+                state_lock.clone(),
+                scheduled,
+                group_place,
+            );
+        }
+        for key in rescheduled {
+            let activity = activities.iter().find(|it| it.key() == key).unwrap();
+            schedule.retain(|it| it.activity.key() != key);
+            schedule.extend(schedule_activity(&args, &api, activity, &fired).await);
+            log_schedule(&schedule);
+        }
+
+        let next_fire = schedule.iter().map(|it| it.opens_at).min();
+        let wake_at = next_fire.map_or(next_refresh, |it| it.min(next_refresh));
+        let sleep = (wake_at - server_now()).to_std().unwrap_or_default();
+        tokio::time::sleep(sleep).await;
+    }
+}
+
+/// Logs how far the local clock is off from the API's and warns above
+/// `--max-clock-skew-ms`. Returns the skew to schedule by, which is zero
+/// unless `--compensate-clock-skew` is set.
+async fn measure_clock_skew(args: &Args, api: &Api) -> Duration {
+    let skew = match api.clock_skew().await {
+        Ok(Some(skew)) => skew,
+        Ok(None) => {
+            info!("unable to measure the clock skew, the api sent no date");
+            return Duration::zero();
+        }
+        Err(err) => {
+            warn!("unable to measure the clock skew: {}", err);
+            return Duration::zero();
+        }
+    };
+    info!(
+        "the api clock is {} ms ahead of the local clock, give or take 500 ms",
+        skew.num_milliseconds()
+    );
+    if skew.num_milliseconds().abs() > args.max_clock_skew_ms {
+        warn!(
+            "the local clock is off by more than {} ms, bookings fire {} unless --compensate-clock-skew is set",
+            args.max_clock_skew_ms,
+            if skew > Duration::zero() { "late" } else { "early" }
+        );
+    }
+    if args.compensate_clock_skew {
+        info!("scheduling by the api clock");
+        skew
+    } else {
+        Duration::zero()
+    }
+}
+
+/// Every upcoming class of the activity within the horizon that isn't booked
+/// yet. Classes whose booking already opened are only kept while bookable.
+async fn schedule_activity(
+    args: &Args,
+    api: &Api,
+    activity: &BookableActivity,
+    fired: &HashSet<(String, NaiveDate)>,
+) -> Vec<Scheduled> {
+    let now = Utc::now();
+    let url = get_bookings_url(
+        &activity.user_id.to_string(),
+        &activity.id,
+        &now,
+        &(now + Duration::weeks(args.horizon_weeks)),
+    );
+    let mut dto = match api.get_group_activities(&url).await {
+        Ok(dto) => dto,
+        Err(err) => {
+            error!(
+                "unable to schedule {} for user {}: {}",
+                &activity.name, &activity.user_name, err
+            );
+            return vec![];
+        }
+    };
+    selection::cap_candidates(args, activity, &mut dto.group_activities);
+    selection::warn_if_misnamed(activity, &dto.group_activities);
+    let candidates: Vec<_> = dto
+        .group_activities
+        .into_iter()
+        .filter(|it| selection::is_match(activity, it) && it.booking_id.is_none())
+        .filter(|it| {
+            let date = parse_date(&it.start_time).date_naive();
+            !fired.contains(&(activity.key(), date))
+        })
+        .filter(|it| {
+            let opens_at = activity.booking_opens_at(args, it);
+            let is_closed = opens_at <= now && !bookability::is_candidate(args, activity, it);
+            !selection::has_passed(activity, it, &now) && !is_closed
+        })
+        .collect();
+
+    // Like a regular run, only one class is booked per day
+    let mut dates: Vec<_> = candidates
+        .iter()
+        .map(|it| parse_date(&it.start_time).date_naive())
+        .collect();
+    dates.sort();
+    dates.dedup();
+    dates
+        .into_iter()
+        .filter_map(|date| {
+            let same_day = candidates
+                .iter()
+                .filter(|it| parse_date(&it.start_time).date_naive() == date);
+            let class = selection::select_best(args, activity, same_day)?
+                .class
+                .clone();
+            let opens_at = activity.booking_opens_at(args, &class);
+            Some(Scheduled {
+                activity: activity.clone(),
+                class,
+                opens_at,
+            })
+        })
+        .collect()
+}
+
+fn log_schedule(schedule: &[Scheduled]) {
+    let mut sorted: Vec<_> = schedule.iter().collect();
+    sorted.sort_by_key(|it| it.opens_at);
+    info!("{} classes scheduled", sorted.len());
+    for scheduled in sorted {
+        info!(
+            "{} at {} for user {} opens {}",
+            scheduled.class.name,
+            scheduled.class.start_time,
+            scheduled.activity.user_name,
+            scheduled
+                .opens_at
+                .with_timezone(&CLUB_TZ)
+                .format("%Y-%m-%d %H:%M:%S %Z")
+        );
+    }
+}
+
+fn fire(
+    args: Arc<Args>,
+    api: Arc<Api>,
+    notifier: Arc<Notifier>,
+    state_lock: Option<Arc<Mutex<()>>>,
+    scheduled: Scheduled,
+    group_place: Option<GroupPlace>,
+) {
+    tokio::task::spawn(async move {
+        let Scheduled {
+            activity, class, ..
+        } = scheduled;
+        info!(
+            "booking of {} at {} opened, booking it for user {}",
+            class.name, class.start_time, activity.user_name
+        );
+        let mut decision = Decision::start(activity.key());
+        decision.reason = Some(Reason::Scheduled);
+        let class = match activity.resolve_on_fire.unwrap_or(false) {
+            true => resolve(&args, &api, &activity, class).await,
+            false => Some(class),
+        };
+        let Some(class) = class else {
+            if let Some(place) = group_place {
+                place.release();
+            }
+            decision.finish(&BookingOutcome::NotFound);
+            logging::log_decision(&args, &decision);
+            let reports = [activity.report(BookingOutcome::NotFound)];
+            api.record_decision(&reports[0]);
+            notifier.notify(&reports).await;
+            hooks::run(&args, &reports).await;
+            return;
+        };
+        decision.class_id = Some(class.id);
+        decision.candidates = 1;
+        let outcome = match book_class(&args, &api, &activity, &class).await {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                error!("{}", err.to_string());
+                if let (Some(lock), Some(path)) = (&state_lock, &args.state) {
+                    let _guard = lock.lock().await;
+                    if retry_queue::enqueue(&args, path, &activity, &class, &err) {
+                        return;
+                    }
+                }
+                BookingOutcome::Failed {
+                    reason: err.to_string(),
+                }
+            }
+        };
+        decision.booking_ms = Some(decision.elapsed_ms());
+        decision.finish(&outcome);
+        logging::log_decision(&args, &decision);
+        let secured = matches!(
+            outcome,
+            BookingOutcome::Booked { .. } | BookingOutcome::WouldBook { .. }
+        );
+        if let (false, Some(place)) = (secured, group_place) {
+            place.release();
+        }
+        let mut report = activity.report(outcome);
+        report.decision = Some(decision);
+        api.record_decision(&report);
+        if let (true, Some(lock), Some(path)) = (args.rolling, state_lock, &args.state) {
+            let _guard = lock.lock().await;
+            let date = parse_date(&class.start_time).date_naive();
+            let mut state = State::load(path);
+            state.record(std::slice::from_ref(&report), Utc::now());
+            let today = Utc::now().with_timezone(&CLUB_TZ).date_naive();
+            for activity in state.activities.values_mut() {
+                activity.secured.retain(|it| *it >= today);
+            }
+            if secured {
+                let entry = state.activities.entry(activity.key()).or_default();
+                entry.secured.push(date);
+                info!(
+                    "secured {} at {} for user {}, {} upcoming occurrences booked ahead",
+                    class.name,
+                    class.start_time,
+                    activity.user_name,
+                    entry.secured.len()
+                );
+            }
+            if let Err(err) = state.save(path) {
+                error!("unable to save state to {}: {}", path.display(), err);
+            }
+        }
+        let reports = [report];
+        notifier.notify(&reports).await;
+        hooks::run(&args, &reports).await;
+    });
+}
+
+/// Fetches the day of the scheduled class again and picks the class to book
+/// from scratch, for `resolve_on_fire`. When the fetch fails the scheduled
+/// class is booked as is, since the booking has just opened.
+async fn resolve(
+    args: &Args,
+    api: &Api,
+    activity: &BookableActivity,
+    scheduled: GroupActivity,
+) -> Option<GroupActivity> {
+    let start = parse_date(&scheduled.start_time);
+    let day = start.with_timezone(&Utc);
+    let url = get_bookings_url(&activity.user_id.to_string(), &activity.id, &day, &day);
+    let mut dto = match api.get_group_activities(&url).await {
+        Ok(dto) => dto,
+        Err(err) => {
+            warn!(
+                "unable to resolve {} again, booking the scheduled class: {}",
+                scheduled.name, err
+            );
+            return Some(scheduled);
+        }
+    };
+    selection::cap_candidates(args, activity, &mut dto.group_activities);
+    let same_day = dto.group_activities.iter().filter(|it| {
+        selection::is_match(activity, it)
+            && parse_date(&it.start_time).date_naive() == start.date_naive()
+            && bookability::is_candidate(args, activity, it)
+    });
+    let class = selection::select_best(args, activity, same_day)?
+        .class
+        .clone();
+    if class.id != scheduled.id {
+        info!(
+            "{} at {} resolved to class {} instead of the scheduled {}",
+            class.name, class.start_time, class.id, scheduled.id
+        );
+    }
+    Some(class)
+}
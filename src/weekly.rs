@@ -0,0 +1,94 @@
+//! `--weekly-report` looks back at the classes booked in a week, from the
+//! history in the state file. Only bookings made since the history was added
+//! to the state file show up, and only with `--state` set when booking.
+use crate::cli::ReportFormat;
+use crate::state::{BookedClass, State};
+use crate::tz::parse_date;
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
+use std::collections::BTreeMap;
+
+/// The monday of the week given as `2024-W10` or any date within it
+pub fn parse_week(value: &str) -> Result<NaiveDate, String> {
+    if let Some((year, week)) = value.split_once("-W") {
+        let year = year.parse().map_err(|_| format!("invalid year {}", year))?;
+        let week = week.parse().map_err(|_| format!("invalid week {}", week))?;
+        return NaiveDate::from_isoywd_opt(year, week, Weekday::Mon)
+            .ok_or_else(|| format!("{} has no week {}", year, week));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("expected a week like 2024-W10 or a date, got {}", value))?;
+    Ok(date - Duration::days(date.weekday().num_days_from_monday() as i64))
+}
+
+pub fn text(state: &State, monday: NaiveDate, format: ReportFormat) -> String {
+    let sunday = monday + Duration::days(6);
+    let mut classes: Vec<_> = state
+        .activities
+        .values()
+        .flat_map(|it| &it.history)
+        .filter(|it| {
+            let date = parse_date(&it.start_time).date_naive();
+            monday <= date && date <= sunday
+        })
+        .collect();
+    classes.sort_by(|a, b| {
+        (&a.user_name, parse_date(&a.start_time)).cmp(&(&b.user_name, parse_date(&b.start_time)))
+    });
+    match format {
+        ReportFormat::Table => table(&classes, monday, sunday),
+        ReportFormat::Csv => csv(&classes),
+    }
+}
+
+fn table(classes: &[&BookedClass], monday: NaiveDate, sunday: NaiveDate) -> String {
+    let mut text = format!(
+        "Week {} ({} to {})",
+        monday.format("%G-W%V"),
+        monday,
+        sunday
+    );
+    let mut users: BTreeMap<&str, Vec<&BookedClass>> = BTreeMap::new();
+    for class in classes {
+        users.entry(&class.user_name).or_default().push(class);
+    }
+    for (user_name, classes) in &users {
+        text += &format!("\n\n{}", user_name);
+        for class in classes {
+            let start = parse_date(&class.start_time);
+            text += &format!(
+                "\n  {:<9} {} {}  {}",
+                start.format("%A").to_string().to_lowercase(),
+                start.format("%Y-%m-%d"),
+                start.format("%H:%M"),
+                class.class
+            );
+        }
+        text += &format!("\n  {} classes", classes.len());
+    }
+    text += &format!("\n\n{} classes booked", classes.len());
+    text
+}
+
+fn csv(classes: &[&BookedClass]) -> String {
+    let mut text = String::from("user,date,day,time,class");
+    for class in classes {
+        let start = parse_date(&class.start_time);
+        text += &format!(
+            "\n{},{},{},{},{}",
+            csv_field(&class.user_name),
+            start.format("%Y-%m-%d"),
+            start.format("%A").to_string().to_lowercase(),
+            start.format("%H:%M"),
+            csv_field(&class.class)
+        );
+    }
+    text
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}